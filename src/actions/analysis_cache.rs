@@ -0,0 +1,217 @@
+// Copyright 2017 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Persists the analysis data of the primary crates in the target directory,
+//! so that it can be loaded when the RLS starts, rather than only once the
+//! initial build has finished.
+
+use build;
+use data::Analysis;
+use serde_json;
+
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::fs::{self, File};
+use std::hash::{Hash, Hasher};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+const CACHE_FILE_NAME: &'static str = "analysis-cache.json";
+
+#[derive(Serialize, Deserialize)]
+struct CacheFile {
+    compiler_version: String,
+    crates: Vec<CachedCrate>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CachedCrate {
+    // Hashes of the contents of the source files at the time of the build.
+    sources: HashMap<PathBuf, u64>,
+    analysis: Analysis,
+}
+
+// The cache file as it's written, which borrows the analysis rather than
+// copying it.
+#[derive(Serialize)]
+struct StoredCacheFile<'a> {
+    compiler_version: &'a str,
+    crates: Vec<StoredCrate<'a>>,
+}
+
+#[derive(Serialize)]
+struct StoredCrate<'a> {
+    sources: HashMap<PathBuf, u64>,
+    analysis: &'a Analysis,
+}
+
+/// The analysis cache of a single project.
+#[derive(Clone, Debug)]
+pub struct AnalysisCache {
+    path: PathBuf,
+    project_dir: PathBuf,
+    sysroot: Option<String>,
+}
+
+impl AnalysisCache {
+    /// The cache is stored in the given target dir, or the one the RLS uses
+    /// by default. `sysroot` is the one from the config, if set.
+    pub fn new(project_dir: &Path, target_dir: Option<&Path>, sysroot: Option<&str>) -> AnalysisCache {
+        let target_dir = target_dir.map(|d| d.to_owned())
+            .unwrap_or_else(|| project_dir.join("target").join("rls"));
+        AnalysisCache {
+            path: target_dir.join(CACHE_FILE_NAME),
+            project_dir: project_dir.to_owned(),
+            sysroot: sysroot.map(|s| s.to_owned()),
+        }
+    }
+
+    // The analysis data depends on both the compiler linked into the RLS and
+    // the toolchain the dependencies (and std) of the project have been
+    // compiled with.
+    fn compiler_version(&self) -> Option<String> {
+        let hash = build::toolchain_hash(&self.project_dir, self.sysroot.as_ref().map(|s| &s[..]))?;
+        Some(format!("rls {}; rustc {}", ::version(), hash))
+    }
+
+    /// Returns the cached analysis of every crate whose sources have not
+    /// changed since it was stored.
+    pub fn load(&self) -> Vec<Analysis> {
+        let cache = match self.read() {
+            Some(cache) => cache,
+            None => return vec![],
+        };
+
+        cache.crates.into_iter()
+            .filter(|krate| sources_unchanged(&krate.sources, &self.project_dir))
+            .map(|krate| krate.analysis)
+            .collect()
+    }
+
+    /// Stores the analysis of every primary crate, as built by Cargo,
+    /// replacing the cached analysis.
+    pub fn store(&self, analysis: &[Analysis]) {
+        let compiler_version = match self.compiler_version() {
+            Some(version) => version,
+            None => {
+                debug!("Not storing the analysis cache, as the toolchain is unknown");
+                return;
+            }
+        };
+        let crates = analysis.iter()
+            .map(|analysis| StoredCrate {
+                sources: hash_sources(analysis, &self.project_dir),
+                analysis,
+            })
+            .collect();
+
+        let cache = StoredCacheFile {
+            compiler_version: &compiler_version,
+            crates,
+        };
+        if let Err(e) = self.write(&cache) {
+            debug!("Couldn't write the analysis cache to {:?}: {}", self.path, e);
+        }
+    }
+
+    fn read(&self) -> Option<CacheFile> {
+        let file = File::open(&self.path).ok()?;
+        let cache: CacheFile = match serde_json::from_reader(io::BufReader::new(file)) {
+            Ok(cache) => cache,
+            Err(e) => {
+                debug!("Ignoring malformed analysis cache {:?}: {}", self.path, e);
+                return None;
+            }
+        };
+        if Some(&cache.compiler_version) != self.compiler_version().as_ref() {
+            debug!("Ignoring analysis cache from another compiler: {}", cache.compiler_version);
+            return None;
+        }
+        Some(cache)
+    }
+
+    fn write(&self, cache: &StoredCacheFile) -> io::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        // Write to a temporary file first, so that a server starting at the
+        // same time never reads a partially written cache.
+        let tmp_path = self.path.with_extension("json.tmp");
+        {
+            let file = File::create(&tmp_path)?;
+            serde_json::to_writer(io::BufWriter::new(file), cache)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        }
+        fs::rename(&tmp_path, &self.path)
+    }
+}
+
+// Hashes every source file which the analysis refers to.
+fn hash_sources(analysis: &Analysis, project_dir: &Path) -> HashMap<PathBuf, u64> {
+    let files = analysis.defs.iter().map(|d| &d.span.file_name)
+        .chain(analysis.refs.iter().map(|r| &r.span.file_name))
+        .chain(analysis.imports.iter().map(|i| &i.span.file_name));
+
+    let mut sources = HashMap::new();
+    for file in files {
+        if sources.contains_key(file) {
+            continue;
+        }
+        if let Some(hash) = hash_file(&project_dir.join(file)) {
+            sources.insert(file.clone(), hash);
+        }
+    }
+    sources
+}
+
+fn sources_unchanged(sources: &HashMap<PathBuf, u64>, project_dir: &Path) -> bool {
+    sources.iter().all(|(file, hash)| hash_file(&project_dir.join(file)) == Some(*hash))
+}
+
+fn hash_file(path: &Path) -> Option<u64> {
+    let mut contents = String::new();
+    File::open(path).ok()?.read_to_string(&mut contents).ok()?;
+    let mut hasher = DefaultHasher::new();
+    contents.hash(&mut hasher);
+    Some(hasher.finish())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::env;
+    use std::io::Write;
+
+    #[test]
+    fn test_sources_unchanged() {
+        let dir = env::current_dir().unwrap().join("target").join("tests").join("analysis_cache");
+        fs::create_dir_all(&dir).unwrap();
+        let write = |contents: &str| {
+            File::create(dir.join("lib.rs")).unwrap().write_all(contents.as_bytes()).unwrap();
+        };
+
+        write("pub fn foo() {}");
+        let mut sources = HashMap::new();
+        sources.insert(PathBuf::from("lib.rs"), hash_file(&dir.join("lib.rs")).unwrap());
+        assert!(sources_unchanged(&sources, &dir));
+
+        write("pub fn bar() {}");
+        assert!(!sources_unchanged(&sources, &dir));
+
+        // Undoing the change makes the cached analysis usable again.
+        write("pub fn foo() {}");
+        assert!(sources_unchanged(&sources, &dir));
+
+        fs::remove_file(dir.join("lib.rs")).unwrap();
+        assert!(!sources_unchanged(&sources, &dir));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
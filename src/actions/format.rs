@@ -0,0 +1,556 @@
+// Copyright 2017 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Turning formatted text into edits of the original document, which only
+//! touch the lines that actually changed. Replacing the whole document would
+//! move the cursor, drop bookmarks and folds and clutter the undo history.
+//!
+//! Also finding the code to format as it's being typed.
+
+use std::cmp;
+use std::ops::Range as LineRange;
+
+use ls_types::{Position, Range, TextEdit};
+
+// Beyond this many pairs of changed (original, formatted) lines, these are
+// replaced as a whole rather than diffed, to bound the memory used.
+const MAX_DIFF_SIZE: usize = 4_000_000;
+
+/// Returns the edits turning `old` into `new`, each replacing a run of
+/// changed lines, or only the changed part of a line where lines were changed
+/// one for one.
+pub fn text_edits(old: &str, new: &str) -> Vec<TextEdit> {
+    edits(old, new, None)
+}
+
+/// Like `text_edits`, but only returns the edits needed to format the given
+/// lines of `old`. An edit overlapping the selection is cut down to it, but
+/// only where the code before and after the cut is the same in both texts, so
+/// the edits cover whole statements or items of the selection.
+pub fn range_text_edits(old: &str, new: &str, selection: LineRange<usize>) -> Vec<TextEdit> {
+    edits(old, new, Some(selection))
+}
+
+/// Returns the lines of a selection, which is what rustfmt is restricted to
+/// and what `range_text_edits` keeps the edits of. A selection ending at the
+/// start of a line doesn't include it.
+pub fn selected_lines(selection: Range) -> LineRange<usize> {
+    let (start, end) = (selection.start.line as usize, selection.end.line as usize);
+    if selection.end.character == 0 && end > start {
+        start..end
+    } else {
+        start..end + 1
+    }
+}
+
+fn edits(old: &str, new: &str, selection: Option<LineRange<usize>>) -> Vec<TextEdit> {
+    let old_lines = lines(old);
+    let new_lines = lines(new);
+
+    // Formatting usually changes little, so the unchanged start and end of the
+    // text are skipped before diffing the rest.
+    let prefix = old_lines.iter().zip(&new_lines).take_while(|&(a, b)| a == b).count();
+    let suffix = old_lines[prefix..].iter().rev()
+        .zip(new_lines[prefix..].iter().rev())
+        .take_while(|&(a, b)| a == b)
+        .count();
+    let old_changed = &old_lines[prefix..old_lines.len() - suffix];
+    let new_changed = &new_lines[prefix..new_lines.len() - suffix];
+
+    let hunks = if old_changed.len() * new_changed.len() > MAX_DIFF_SIZE {
+        vec![Hunk { old: 0..old_changed.len(), new: 0..new_changed.len() }]
+    } else {
+        diff(old_changed, new_changed)
+    };
+    let hunks = hunks.into_iter().map(|hunk| Hunk {
+        old: prefix + hunk.old.start..prefix + hunk.old.end,
+        new: prefix + hunk.new.start..prefix + hunk.new.end,
+    });
+    let hunks: Vec<Hunk> = match selection {
+        Some(selection) => hunks
+            .filter_map(|hunk| restrict(hunk, &old_lines, &new_lines, &selection))
+            .collect(),
+        None => hunks.collect(),
+    };
+
+    let mut edits = vec![];
+    for hunk in hunks {
+        // Small changes (e.g. of the spacing) are usually made within lines.
+        if hunk.old.len() == hunk.new.len() {
+            for (i, j) in hunk.old.zip(hunk.new) {
+                edits.extend(line_edit(i, old_lines[i], new_lines[j]));
+            }
+            continue;
+        }
+
+        edits.push(TextEdit {
+            range: Range {
+                start: line_start(&old_lines, hunk.old.start),
+                end: line_start(&old_lines, hunk.old.end),
+            },
+            new_text: new_lines[hunk.new].concat(),
+        });
+    }
+    edits
+}
+
+// Cuts the hunk down to the part needed to format the selected lines, or
+// `None` if it doesn't overlap them.
+fn restrict(hunk: Hunk, old_lines: &[&str], new_lines: &[&str], selection: &LineRange<usize>) -> Option<Hunk> {
+    let overlaps = if hunk.old.start == hunk.old.end {
+        selection.start <= hunk.old.start && hunk.old.start <= selection.end
+    } else {
+        hunk.old.start < selection.end && selection.start < hunk.old.end
+    };
+    if !overlaps {
+        return None;
+    }
+
+    let splits = split_points(&hunk, old_lines, new_lines);
+    let &(old_start, new_start) = splits.iter()
+        .rev()
+        .find(|&&(old, _)| old <= selection.start)
+        .unwrap_or(&splits[0]);
+    let &(old_end, new_end) = splits.iter()
+        .find(|&&(old, _)| old >= selection.end)
+        .unwrap_or(&splits[splits.len() - 1]);
+    if old_start == old_end && new_start == new_end {
+        return None;
+    }
+    Some(Hunk { old: old_start..old_end, new: new_start..new_end })
+}
+
+// The pairs of (original, formatted) lines at which the hunk can be split,
+// in order and including its start and end. A line of the original text is a
+// split point if its code starts a line of the formatted text as well, which
+// is found by matching up the code ignoring whitespace (what formatting
+// mostly changes).
+fn split_points(hunk: &Hunk, old_lines: &[&str], new_lines: &[&str]) -> Vec<(usize, usize)> {
+    let (old_code, old_line_starts) = code(&old_lines[hunk.old.clone()]);
+    let (new_code, new_line_starts) = code(&new_lines[hunk.new.clone()]);
+
+    // Where formatting changed the code itself (e.g. added a trailing comma),
+    // it can only be matched up before the first and after the last change.
+    let prefix = old_code.iter().zip(&new_code).take_while(|&(a, b)| a == b).count();
+    let suffix = old_code[prefix..].iter().rev()
+        .zip(new_code[prefix..].iter().rev())
+        .take_while(|&(a, b)| a == b)
+        .count();
+    let matching = |i: usize| if i < prefix {
+        Some(i)
+    } else if old_code.len() - i <= suffix {
+        Some(new_code.len() - (old_code.len() - i))
+    } else {
+        None
+    };
+
+    let mut splits = vec![(hunk.old.start, hunk.new.start)];
+    for (line, &start) in old_line_starts.iter().enumerate().skip(1) {
+        let new_line = start.and_then(&matching)
+            .and_then(|i| new_line_starts.iter().position(|&start| start == Some(i)));
+        if let Some(new_line) = new_line {
+            splits.push((hunk.old.start + line, hunk.new.start + new_line));
+        }
+    }
+    splits.push((hunk.old.end, hunk.new.end));
+    splits
+}
+
+// The non-whitespace characters of the lines, and for each line the index of
+// its first one, if any.
+fn code(lines: &[&str]) -> (Vec<char>, Vec<Option<usize>>) {
+    let mut code = vec![];
+    let mut line_starts = vec![];
+    for line in lines {
+        let start = code.len();
+        code.extend(line.chars().filter(|c| !c.is_whitespace()));
+        line_starts.push(if code.len() > start { Some(start) } else { None });
+    }
+    (code, line_starts)
+}
+
+// The edit replacing only the changed part of a line, if any.
+fn line_edit(line: usize, old: &str, new: &str) -> Option<TextEdit> {
+    let old_chars: Vec<char> = old.chars().collect();
+    let new_chars: Vec<char> = new.chars().collect();
+
+    let prefix = old_chars.iter().zip(&new_chars).take_while(|&(a, b)| a == b).count();
+    if prefix == old_chars.len() && prefix == new_chars.len() {
+        return None;
+    }
+    let suffix = old_chars[prefix..].iter().rev()
+        .zip(new_chars[prefix..].iter().rev())
+        .take_while(|&(a, b)| a == b)
+        .count();
+
+    Some(TextEdit {
+        range: Range {
+            start: Position::new(line as u64, prefix as u64),
+            end: Position::new(line as u64, (old_chars.len() - suffix) as u64),
+        },
+        new_text: new_chars[prefix..new_chars.len() - suffix].iter().cloned().collect(),
+    })
+}
+
+// A run of lines of the original text replaced by a run of new lines.
+#[derive(Debug, PartialEq)]
+struct Hunk {
+    old: LineRange<usize>,
+    new: LineRange<usize>,
+}
+
+// Finds the lines which aren't part of the longest common subsequence of both
+// texts.
+fn diff(old: &[&str], new: &[&str]) -> Vec<Hunk> {
+    let (n, m) = (old.len(), new.len());
+    // `lcs[index(i, j)]` is the length of the longest common subsequence of
+    // `old[i..]` and `new[j..]`.
+    let index = |i: usize, j: usize| i * (m + 1) + j;
+    let mut lcs = vec![0u32; (n + 1) * (m + 1)];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            lcs[index(i, j)] = if old[i] == new[j] {
+                lcs[index(i + 1, j + 1)] + 1
+            } else {
+                cmp::max(lcs[index(i + 1, j)], lcs[index(i, j + 1)])
+            };
+        }
+    }
+
+    let mut hunks = vec![];
+    let mut hunk_start = None;
+    let (mut i, mut j) = (0, 0);
+    while i < n || j < m {
+        if i < n && j < m && old[i] == new[j] {
+            if let Some((old_start, new_start)) = hunk_start.take() {
+                hunks.push(Hunk { old: old_start..i, new: new_start..j });
+            }
+            i += 1;
+            j += 1;
+            continue;
+        }
+
+        if hunk_start.is_none() {
+            hunk_start = Some((i, j));
+        }
+        if j < m && (i == n || lcs[index(i, j + 1)] >= lcs[index(i + 1, j)]) {
+            j += 1;
+        } else {
+            i += 1;
+        }
+    }
+    if let Some((old_start, new_start)) = hunk_start {
+        hunks.push(Hunk { old: old_start..n, new: new_start..m });
+    }
+    hunks
+}
+
+// Splits the text into lines, keeping their line endings.
+fn lines(text: &str) -> Vec<&str> {
+    let mut lines = vec![];
+    let mut start = 0;
+    for (i, c) in text.char_indices() {
+        if c == '\n' {
+            lines.push(&text[start..i + 1]);
+            start = i + 1;
+        }
+    }
+    if start < text.len() {
+        lines.push(&text[start..]);
+    }
+    lines
+}
+
+// The position where the given line starts, or the end of the text when past
+// its last line.
+fn line_start(lines: &[&str], line: usize) -> Position {
+    match lines.last() {
+        Some(last) if line == lines.len() && !last.ends_with('\n') => {
+            Position::new(line as u64 - 1, last.chars().count() as u64)
+        }
+        _ => Position::new(line as u64, 0),
+    }
+}
+
+/// The code before a position in a document, as far as formatting what's
+/// being typed there is concerned.
+#[derive(Debug, PartialEq)]
+pub struct TypedCode {
+    /// The lines of the statement ended by a `;`, or of the block (and the
+    /// statement or item it belongs to) closed by a `}`, if the last code
+    /// before the position is one of these.
+    pub ended: Option<LineRange<usize>>,
+    /// The number of blocks (or parentheses, brackets) open at the position,
+    /// not counting one closed right after it on the same line. This is the
+    /// indentation of the position's line.
+    pub depth: usize,
+}
+
+// A block (or parentheses, brackets) opened in the code.
+struct Frame {
+    // The line of the statement or item the block belongs to.
+    start: usize,
+    // The line the current statement in the block starts on, if one started.
+    statement_start: Option<usize>,
+    // The line of a `}` closing a nested block, after which the statement
+    // ends if the next code is on another line (e.g., after a nested item).
+    closed_on: Option<usize>,
+}
+
+impl Frame {
+    fn new(start: usize) -> Frame {
+        Frame { start, statement_start: None, closed_on: None }
+    }
+}
+
+/// Scans the code before `position` in `text`, skipping comments and
+/// literals. The code doesn't have to be complete or valid.
+pub fn typed_code(text: &str, position: Position) -> TypedCode {
+    let chars: Vec<char> = text.chars().collect();
+    let mut line_of = Vec::with_capacity(chars.len());
+    let mut end = None;
+    let (mut line, mut character) = (0, 0);
+    for (i, &c) in chars.iter().enumerate() {
+        if end.is_none() && (line > position.line || (line == position.line && character >= position.character)) {
+            end = Some(i);
+        }
+        line_of.push(line as usize);
+        if c == '\n' {
+            line += 1;
+            character = 0;
+        } else {
+            character += 1;
+        }
+    }
+    let end = end.unwrap_or(chars.len());
+
+    let mut frames = vec![Frame::new(0)];
+    let mut ended = None;
+    let mut i = 0;
+    while i < end {
+        let c = chars[i];
+        let next = chars.get(i + 1).cloned();
+        if c.is_whitespace() {
+            i += 1;
+            continue;
+        }
+        if c == '/' && next == Some('/') {
+            while i < end && chars[i] != '\n' {
+                i += 1;
+            }
+            continue;
+        }
+        if c == '/' && next == Some('*') {
+            i = skip_block_comment(&chars, i);
+            continue;
+        }
+
+        let line = line_of[i];
+        ended = None;
+        {
+            let frame = frames.last_mut().unwrap();
+            if frame.closed_on.take().map_or(false, |closed_on| closed_on != line) {
+                frame.statement_start = None;
+            }
+            if frame.statement_start.is_none() {
+                frame.statement_start = Some(line);
+            }
+        }
+
+        let identifier_before = i > 0 && (chars[i - 1].is_alphanumeric() || chars[i - 1] == '_');
+        i = match c {
+            '"' => skip_string(&chars, i + 1, 0),
+            'r' if !identifier_before && raw_string_hashes(&chars, i + 1).is_some() => {
+                let hashes = raw_string_hashes(&chars, i + 1).unwrap();
+                skip_string(&chars, i + hashes + 2, hashes)
+            }
+            '\'' => skip_char(&chars, i + 1),
+            '{' | '(' | '[' => {
+                let start = frames.last().unwrap().statement_start.unwrap_or(line);
+                frames.push(Frame::new(start));
+                i + 1
+            }
+            '}' | ')' | ']' => {
+                if frames.len() > 1 {
+                    let frame = frames.pop().unwrap();
+                    if c == '}' {
+                        ended = Some(frame.start..line + 1);
+                        frames.last_mut().unwrap().closed_on = Some(line);
+                    }
+                }
+                i + 1
+            }
+            ';' | ',' => {
+                let frame = frames.last_mut().unwrap();
+                let start = frame.statement_start.take().unwrap_or(line);
+                if c == ';' {
+                    ended = Some(start..line + 1);
+                }
+                i + 1
+            }
+            _ => i + 1,
+        };
+    }
+
+    let mut depth = frames.len() - 1;
+    let closes = chars[end..].iter()
+        .take_while(|&&c| c != '\n')
+        .find(|c| !c.is_whitespace())
+        .map_or(false, |&c| c == '}' || c == ')' || c == ']');
+    if closes && depth > 0 {
+        depth -= 1;
+    }
+
+    TypedCode { ended, depth }
+}
+
+// Skips a (possibly nested) block comment starting at `i`.
+fn skip_block_comment(chars: &[char], mut i: usize) -> usize {
+    let mut depth = 0;
+    while i < chars.len() {
+        let next = chars.get(i + 1).cloned();
+        if chars[i] == '/' && next == Some('*') {
+            depth += 1;
+            i += 2;
+        } else if chars[i] == '*' && next == Some('/') {
+            depth -= 1;
+            i += 2;
+            if depth == 0 {
+                break;
+            }
+        } else {
+            i += 1;
+        }
+    }
+    i
+}
+
+// The number of `#`s of a raw string, if one starts at `i` (after the `r`).
+fn raw_string_hashes(chars: &[char], i: usize) -> Option<usize> {
+    let hashes = chars[i..].iter().take_while(|&&c| c == '#').count();
+    if chars.get(i + hashes) == Some(&'"') {
+        Some(hashes)
+    } else {
+        None
+    }
+}
+
+// Skips the rest of a string starting at `i`, which is closed by a `"`
+// followed by `hashes` `#`s (escapes only apply if it's not a raw string).
+fn skip_string(chars: &[char], mut i: usize, hashes: usize) -> usize {
+    while i < chars.len() {
+        match chars[i] {
+            '\\' if hashes == 0 => i += 2,
+            '"' if chars[i + 1..].iter().take(hashes).filter(|&&c| c == '#').count() == hashes => {
+                return i + 1 + hashes;
+            }
+            _ => i += 1,
+        }
+    }
+    chars.len()
+}
+
+// Skips the rest of a character literal starting at `i`, or nothing if the
+// `'` started a lifetime or label.
+fn skip_char(chars: &[char], i: usize) -> usize {
+    match chars.get(i) {
+        // An escape, e.g. `'\''` or `'\u{7FFF}'`.
+        Some(&'\\') => {
+            let mut i = i + 2;
+            while i < chars.len() && chars[i] != '\'' {
+                i += 1;
+            }
+            cmp::min(i + 1, chars.len())
+        }
+        _ if chars.get(i + 1) == Some(&'\'') => i + 2,
+        _ => i,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_text_edits() {
+        let old = "fn main() {\n  let x=1;\n    println!(\"{}\", x);\n}";
+        let new = "fn main() {\n    let x = 1;\n    println!(\"{}\", x);\n}\n";
+
+        let edits = text_edits(old, new);
+        assert_eq!(edits.len(), 2);
+        assert_eq!(edits[0].range, Range { start: Position::new(1, 2), end: Position::new(1, 8) });
+        assert_eq!(edits[0].new_text, "  let x = ");
+        assert_eq!(edits[1].range, Range { start: Position::new(3, 1), end: Position::new(3, 1) });
+        assert_eq!(edits[1].new_text, "\n");
+
+        assert!(text_edits(new, new).is_empty());
+
+        let old = "fn main() { foo(); }\n";
+        let new = "fn main() {\n    foo();\n}\n";
+        let edits = text_edits(old, new);
+        assert_eq!(edits.len(), 1);
+        assert_eq!(edits[0].range, Range { start: Position::new(0, 0), end: Position::new(1, 0) });
+        assert_eq!(edits[0].new_text, new);
+    }
+
+    #[test]
+    fn test_range_text_edits() {
+        let old = "fn main()\n{\nlet x=1; foo(x);\nlet y=2;\n}\n";
+        let new = "fn main() {\n    let x = 1;\n    foo(x);\n    let y = 2;\n}\n";
+
+        let edits = range_text_edits(old, new, 2..3);
+        assert_eq!(edits.len(), 1);
+        assert_eq!(edits[0].range, Range { start: Position::new(2, 0), end: Position::new(3, 0) });
+        assert_eq!(edits[0].new_text, "    let x = 1;\n    foo(x);\n");
+
+        // The brace can't be formatted without the line before it.
+        let edits = range_text_edits(old, new, 1..2);
+        assert_eq!(edits.len(), 1);
+        assert_eq!(edits[0].range, Range { start: Position::new(0, 0), end: Position::new(2, 0) });
+        assert_eq!(edits[0].new_text, "fn main() {\n");
+
+        let ranges = |edits: Vec<TextEdit>| edits.into_iter().map(|e| e.range).collect::<Vec<_>>();
+        assert_eq!(ranges(range_text_edits(old, new, 0..5)), ranges(text_edits(old, new)));
+        assert!(range_text_edits(old, new, 5..6).is_empty());
+    }
+
+    #[test]
+    fn test_selected_lines() {
+        let range = |start: (u64, u64), end: (u64, u64)| {
+            Range { start: Position::new(start.0, start.1), end: Position::new(end.0, end.1) }
+        };
+        assert_eq!(selected_lines(range((1, 4), (3, 2))), 1..4);
+        // Selecting whole lines ends at the start of the next one.
+        assert_eq!(selected_lines(range((1, 0), (3, 0))), 1..3);
+        assert_eq!(selected_lines(range((1, 0), (1, 0))), 1..2);
+    }
+
+    #[test]
+    fn test_typed_code() {
+        let text = "fn main() {\n    let x = foo(1,\n        2);\n    if x {\n        bar(\"}\");\n    }\n    \n}\n";
+        assert_eq!(typed_code(text, Position::new(2, 11)), TypedCode { ended: Some(1..3), depth: 1 });
+        assert_eq!(typed_code(text, Position::new(4, 17)), TypedCode { ended: Some(4..5), depth: 2 });
+        assert_eq!(typed_code(text, Position::new(5, 5)), TypedCode { ended: Some(3..6), depth: 1 });
+        assert_eq!(typed_code(text, Position::new(6, 4)), TypedCode { ended: Some(3..6), depth: 1 });
+        assert_eq!(typed_code(text, Position::new(7, 0)), TypedCode { ended: Some(3..6), depth: 0 });
+
+        let text = "fn f<'a>(x: &'a str) -> char { // }\n    '}'\n}\n";
+        assert_eq!(typed_code(text, Position::new(1, 7)), TypedCode { ended: None, depth: 1 });
+    }
+
+    #[test]
+    fn test_diff() {
+        let old = ["a", "b", "c", "d"];
+        let new = ["a", "c", "x", "d", "e"];
+        assert_eq!(diff(&old, &new), vec![Hunk { old: 1..2, new: 1..1 },
+                                          Hunk { old: 3..3, new: 2..3 },
+                                          Hunk { old: 4..4, new: 4..5 }]);
+    }
+}
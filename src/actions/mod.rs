@@ -13,18 +13,19 @@
 
 use analysis::AnalysisHost;
 use vfs::Vfs;
-use config::{Config, FmtConfig};
+use config::{Config, FmtConfigCache};
 use serde_json;
 use url::Url;
 use span;
 use Span;
 
+use actions::analysis_cache::AnalysisCache;
 use actions::post_build::{BuildResults, PostBuildHandler};
 use build::*;
 use lsp_data::*;
 use server::Output;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 use std::thread;
@@ -48,7 +49,10 @@ macro_rules! parse_file_path {
     }
 }
 
+mod analysis_cache;
+mod format;
 mod post_build;
+mod rename;
 pub mod requests;
 pub mod notifications;
 
@@ -69,10 +73,15 @@ impl ActionContext {
     }
 
     /// Initialize this context. Panics if it has already been initialized.
-    pub fn init<O: Output>(&mut self, current_project: PathBuf, init_options: &InitializationOptions, out: O) {
+    pub fn init<O: Output>(&mut self,
+                           current_project: PathBuf,
+                           init_options: &InitializationOptions,
+                           dynamic_rename: bool,
+                           out: O) {
         let ctx = match *self {
             ActionContext::Uninit(ref uninit) => {
-                let ctx = InitActionContext::new(uninit.analysis.clone(), uninit.vfs.clone(), uninit.config.clone(), current_project);
+                let ctx = InitActionContext::new(uninit.analysis.clone(), uninit.vfs.clone(), uninit.config.clone(),
+                                                 current_project, dynamic_rename);
                 ctx.init(init_options, out);
                 ctx
             }
@@ -96,12 +105,27 @@ pub struct InitActionContext {
     vfs: Arc<Vfs>,
 
     current_project: PathBuf,
+    // Files the client has open, whose contents it keeps up to date in the
+    // VFS itself.
+    open_files: Arc<Mutex<HashSet<PathBuf>>>,
 
     previous_build_results: Arc<Mutex<BuildResults>>,
     build_queue: BuildQueue,
+    // Set once a build has loaded its analysis, after which the (possibly
+    // stale) cached analysis must not be loaded anymore.
+    build_analysis_loaded: Arc<Mutex<bool>>,
 
     config: Arc<Mutex<Config>>,
-    fmt_config: FmtConfig,
+    fmt_config: FmtConfigCache,
+    // Files for which rustfmt's errors were published, which are cleared once
+    // they're formatted without errors.
+    fmt_error_files: Mutex<HashSet<PathBuf>>,
+    // Whether `textDocument/willSaveWaitUntil` is registered with the client,
+    // which is only the case with `format_on_save`.
+    will_save_wait_until_registered: Mutex<bool>,
+    // Whether rename is registered dynamically once the client is
+    // initialized, rather than being a static capability.
+    dynamic_rename: bool,
 }
 
 /// Persistent context shared across all requests and actions before the RLS has
@@ -129,17 +153,23 @@ impl InitActionContext {
     fn new(analysis: Arc<AnalysisHost>,
                vfs: Arc<Vfs>,
                config: Arc<Mutex<Config>>,
-               current_project: PathBuf) -> InitActionContext {
+               current_project: PathBuf,
+               dynamic_rename: bool) -> InitActionContext {
         let build_queue = BuildQueue::new(vfs.clone(), config.clone());
-        let fmt_config = FmtConfig::from(&current_project);
+        let fmt_config = FmtConfigCache::new();
         InitActionContext {
             analysis,
             vfs,
             config,
             current_project,
+            open_files: Arc::new(Mutex::new(HashSet::new())),
             previous_build_results: Arc::new(Mutex::new(HashMap::new())),
             build_queue,
+            build_analysis_loaded: Arc::new(Mutex::new(false)),
             fmt_config,
+            fmt_error_files: Mutex::new(HashSet::new()),
+            will_save_wait_until_registered: Mutex::new(false),
+            dynamic_rename,
         }
     }
 
@@ -155,21 +185,67 @@ impl InitActionContext {
             }
         });
 
+        self.load_cached_analysis();
+
         if !init_options.omit_init_build {
             self.build_current_project(BuildPriority::Cargo, out);
         }
     }
 
+    fn analysis_cache(&self, project_path: &Path) -> AnalysisCache {
+        let config = self.config.lock().unwrap();
+        AnalysisCache::new(project_path,
+                           config.target_dir.as_ref().map(|d| d as &Path),
+                           config.sysroot.as_ref().map(|s| &s[..]))
+    }
+
+    // Loads the analysis persisted by a previous session, so that navigation
+    // works before the initial build is done. Runs on another thread, since
+    // the cache of a large project can take a while to read.
+    fn load_cached_analysis(&self) {
+        let cache = self.analysis_cache(&self.current_project);
+        let crate_blacklist = self.config.lock().unwrap().crate_blacklist();
+        let analysis = self.analysis.clone();
+        let build_analysis_loaded = self.build_analysis_loaded.clone();
+        let project_path = self.current_project.clone();
+        thread::spawn(move || {
+            let cached = cache.load();
+            if cached.is_empty() {
+                return;
+            }
+
+            let build_analysis_loaded = build_analysis_loaded.lock().unwrap();
+            if *build_analysis_loaded {
+                return;
+            }
+            debug!("loading cached analysis: {:?}", project_path);
+            let cwd = ::std::env::current_dir().unwrap();
+            let blacklist: Vec<&str> = crate_blacklist.iter().map(|name| &name[..]).collect();
+            if let Err(e) = analysis.reload_from_analysis(cached, &project_path, &cwd, &blacklist) {
+                debug!("Couldn't load the cached analysis: {:?}", e);
+            }
+        });
+    }
+
     fn build<O: Output>(&self, project_path: &Path, priority: BuildPriority, out: O) {
+        let analysis_cache = self.analysis_cache(project_path);
         let pbh = {
             let config = self.config.lock().unwrap();
             PostBuildHandler {
                 analysis: self.analysis.clone(),
+                analysis_cache,
+                build_analysis_loaded: self.build_analysis_loaded.clone(),
                 previous_build_results: self.previous_build_results.clone(),
                 project_path: project_path.to_owned(),
                 out: out.clone(),
                 show_warnings: config.show_warnings,
-                use_black_list: config.use_crate_blacklist,
+                open_files: if config.clear_diagnostics_on_close {
+                    Some(self.open_files.clone())
+                } else {
+                    None
+                },
+                crate_blacklist: config.crate_blacklist(),
+                build_telemetry: config.build_telemetry,
             }
         };
 
@@ -177,8 +253,8 @@ impl InitActionContext {
             NOTIFICATION_BUILD_BEGIN,
             None,
         ));
-        self.build_queue.request_build(project_path, priority, move |result| {
-            pbh.handle(result)
+        self.build_queue.request_build(project_path, priority, move |result, stats| {
+            pbh.handle(result, stats)
         });
     }
 
@@ -227,46 +303,102 @@ fn find_word_at_pos(line: &str, pos: &Column) -> (Column, Column) {
 
 // TODO include workspace Cargo.tomls in watchers / relevant
 /// Client file-watching request / filtering logic
-/// We want to watch workspace 'Cargo.toml', root 'Cargo.lock' & the root 'target' dir
+/// We want to watch workspace 'Cargo.toml', root 'Cargo.lock', '.cargo/config',
+/// 'rust-toolchain', the root 'target' dir, Rust source files (including build
+/// scripts) and 'rustfmt.toml' / '.rustfmt.toml' files
 pub struct FileWatch<'ctx> {
     project_str: &'ctx str,
     project_uri: String,
+    // The target dir from the config (with a trailing '/'), if set.
+    target_dir_uri: Option<String>,
 }
 
 impl<'ctx> FileWatch<'ctx> {
     /// Construct a new `FileWatch`.
     pub fn new(ctx: &'ctx InitActionContext) -> Self {
+        let target_dir = ctx.config.lock().unwrap().target_dir.clone();
         Self {
             project_str: ctx.current_project.to_str().unwrap(),
             project_uri: Url::from_file_path(&ctx.current_project).unwrap().into_string(),
+            target_dir_uri: target_dir.and_then(|dir| Url::from_file_path(ctx.current_project.join(dir)).ok())
+                .map(|uri| format!("{}/", uri.as_str().trim_right_matches('/'))),
         }
     }
 
     /// Returns json config for desired file watches
     pub fn watchers_config(&self) -> serde_json::Value {
         let pattern = format!("{}/Cargo{{.toml,.lock}}", self.project_str);
+        let cargo_config_pattern = format!("{}/.cargo/config", self.project_str);
+        let toolchain_pattern = format!("{}/rust-toolchain", self.project_str);
+        let source_pattern = format!("{}/**/*.rs", self.project_str);
+        let rustfmt_pattern = format!("{}/**/{{rustfmt.toml,.rustfmt.toml}}", self.project_str);
         let target_pattern = format!("{}/target", self.project_str);
         // For target, we only watch if it gets deleted.
         json!({
-            "watchers": [{ "globPattern": pattern }, { "globPattern": target_pattern, "kind": 4 }]
+            "watchers": [
+                { "globPattern": pattern },
+                { "globPattern": cargo_config_pattern },
+                { "globPattern": toolchain_pattern },
+                { "globPattern": source_pattern },
+                { "globPattern": rustfmt_pattern },
+                { "globPattern": target_pattern, "kind": 4 }
+            ]
         })
     }
 
-    /// Returns if a file change is relevant to the files we actually wanted to watch
+    /// Returns if a file change is relevant to the files we actually wanted to
+    /// watch and requires Cargo to be rerun, i.e. it's a change to a manifest,
+    /// the Cargo config or the toolchain pinned for the project. Build scripts
+    /// are source files of their own units instead.
     // Implementation note: This is expected to be called a large number of times in a loop
     // so should be fast / avoid allocation.
     #[inline]
     pub fn is_relevant(&self, change: &FileEvent) -> bool {
+        let local = match self.local_path(change) {
+            Some(local) => local,
+            None => return false,
+        };
+
+        local == "/Cargo.lock" || local == "/Cargo.toml" || local == "/.cargo/config"
+            || local == "/rust-toolchain"
+            || local == "/target" && change.typ == FileChangeType::Deleted
+    }
+
+    /// Returns if a file change is to a Rust source file of the project. Files
+    /// in the 'target' dir (or the one from the config) are generated by the
+    /// builds themselves, so these are ignored.
+    #[inline]
+    pub fn is_source(&self, change: &FileEvent) -> bool {
+        if let Some(ref target_dir_uri) = self.target_dir_uri {
+            if change.uri.as_str().starts_with(target_dir_uri) {
+                return false;
+            }
+        }
+        match self.local_path(change) {
+            Some(local) => local.ends_with(".rs") && !local.starts_with("/target/"),
+            None => false,
+        }
+    }
+
+    /// Returns if a file change is to a rustfmt config file of the project
+    #[inline]
+    pub fn is_fmt_config(&self, change: &FileEvent) -> bool {
+        match self.local_path(change) {
+            Some(local) => local.ends_with("/rustfmt.toml") || local.ends_with("/.rustfmt.toml"),
+            None => false,
+        }
+    }
+
+    // The path of the changed file relative to the project root (with a
+    // leading '/'), if it's in the project.
+    fn local_path<'a>(&self, change: &'a FileEvent) -> Option<&'a str> {
         let path = change.uri.as_str();
 
         if !path.starts_with(&self.project_uri) {
-            return false;
+            return None;
         }
 
-        let local = &path[self.project_uri.len()..];
-
-        local == "/Cargo.lock" || local == "/Cargo.toml"
-            || local == "/target" && change.typ == FileChangeType::Deleted
+        Some(&path[self.project_uri.len()..])
     }
 }
 
@@ -304,4 +436,41 @@ mod test {
         assert_range("span::Position<|T>", (15, 16));
         assert_range("span::Position<T|>", (15, 16));
     }
+
+    #[test]
+    fn test_file_watch() {
+        let file_watch = FileWatch {
+            project_str: "/project",
+            project_uri: "file:///project".to_owned(),
+            target_dir_uri: Some("file:///project/out/".to_owned()),
+        };
+        let change = |path: &str, typ| FileEvent {
+            uri: Url::parse(&format!("file://{}", path)).unwrap(),
+            typ,
+        };
+
+        assert!(file_watch.is_relevant(&change("/project/Cargo.toml", FileChangeType::Changed)));
+        assert!(file_watch.is_relevant(&change("/project/.cargo/config", FileChangeType::Created)));
+        assert!(file_watch.is_relevant(&change("/project/rust-toolchain", FileChangeType::Changed)));
+        assert!(file_watch.is_relevant(&change("/project/target", FileChangeType::Deleted)));
+        assert!(!file_watch.is_relevant(&change("/project/target", FileChangeType::Changed)));
+        assert!(!file_watch.is_relevant(&change("/project/src/main.rs", FileChangeType::Changed)));
+        assert!(!file_watch.is_relevant(&change("/project/foo/build.rs", FileChangeType::Changed)));
+        assert!(!file_watch.is_relevant(&change("/other/Cargo.toml", FileChangeType::Changed)));
+
+        assert!(file_watch.is_source(&change("/project/src/main.rs", FileChangeType::Changed)));
+        assert!(file_watch.is_source(&change("/project/src/lib.rs", FileChangeType::Deleted)));
+        assert!(file_watch.is_source(&change("/project/foo/build.rs", FileChangeType::Changed)));
+        assert!(!file_watch.is_source(&change("/project/target/debug/build/foo/out/gen.rs",
+                                              FileChangeType::Created)));
+        assert!(!file_watch.is_source(&change("/project/out/rls/debug/build/foo/out/gen.rs",
+                                              FileChangeType::Created)));
+        assert!(file_watch.is_source(&change("/project/output.rs", FileChangeType::Changed)));
+        assert!(!file_watch.is_source(&change("/project/rustfmt.toml", FileChangeType::Changed)));
+        assert!(!file_watch.is_source(&change("/other/src/main.rs", FileChangeType::Changed)));
+
+        assert!(file_watch.is_fmt_config(&change("/project/rustfmt.toml", FileChangeType::Changed)));
+        assert!(file_watch.is_fmt_config(&change("/project/foo/.rustfmt.toml", FileChangeType::Created)));
+        assert!(!file_watch.is_fmt_config(&change("/project/Cargo.toml", FileChangeType::Changed)));
+    }
 }
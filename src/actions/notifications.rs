@@ -11,9 +11,10 @@
 //! One-way notifications that the RLS receives from the client.
 
 use actions::ActionContext;
-use actions::FileWatch;
-use vfs::Change;
-use config::Config;
+use actions::{requests, FileWatch};
+use actions::post_build::emit_file_notifications;
+use vfs::{Change, FileContents};
+use config::{is_fmt_config, Config};
 use serde::Deserialize;
 use serde::de::Error;
 use serde_json;
@@ -23,6 +24,8 @@ use build::*;
 use lsp_data::*;
 use server::{Output, Action, NotificationAction, LsState, NoParams};
 
+use std::fs::File;
+use std::io::Read;
 use std::thread;
 
 /// Notification from the client that it has completed initialization.
@@ -43,14 +46,28 @@ impl<'a> NotificationAction<'a> for Initialized {
     // dynamically register some options.
     fn handle<O: Output>(&mut self, _params: Self::Params, ctx: &mut ActionContext, out: O) -> Result<(), ()> {
         const WATCH_ID: &'static str = "rls-watch";
+        const RENAME_ID: &'static str = "rls-rename";
 
         let ctx = ctx.inited();
 
         let options = FileWatch::new(&ctx).watchers_config();
+        let mut registrations = vec![Registration { id: WATCH_ID.to_owned(), method: NOTIFICATION__DidChangeWatchedFiles.to_owned(), register_options: options }];
+        // Replaces the static rename capability, to announce `prepareRename`
+        // support.
+        if ctx.dynamic_rename {
+            let rename_options = json!({ "prepareProvider": true });
+            registrations.push(Registration { id: RENAME_ID.to_owned(), method: requests::Rename::METHOD.to_owned(), register_options: rename_options });
+        }
+        // The config may have enabled `format_on_save` already.
+        let format_on_save = ctx.config.lock().unwrap().format_on_save;
+        if format_on_save {
+            *ctx.will_save_wait_until_registered.lock().unwrap() = true;
+            registrations.push(will_save_wait_until_registration());
+        }
         let output = serde_json::to_string(
             &RequestMessage::new(out.provide_id(),
                                  NOTIFICATION__RegisterCapability.to_owned(),
-                                 RegistrationParams { registrations: vec![Registration { id: WATCH_ID.to_owned(), method: NOTIFICATION__DidChangeWatchedFiles.to_owned(), register_options: options } ]})
+                                 RegistrationParams { registrations })
         ).unwrap();
         out.response(output);
         Ok(())
@@ -72,12 +89,79 @@ impl<'a> Action<'a> for DidOpen {
 }
 
 impl<'a> NotificationAction<'a> for DidOpen {
-    fn handle<O: Output>(&mut self, params: Self::Params, ctx: &mut ActionContext, _out: O) -> Result<(), ()> {
+    fn handle<O: Output>(&mut self, params: Self::Params, ctx: &mut ActionContext, out: O) -> Result<(), ()> {
         trace!("on_open: {:?}", params.text_document.uri);
         let ctx = ctx.inited();
         let file_path = parse_file_path!(&params.text_document.uri, "on_open")?;
 
         ctx.vfs.set_file(&file_path, &params.text_document.text);
+        ctx.open_files.lock().unwrap().insert(file_path.clone());
+
+        // Only open files have their diagnostics published, so the ones of
+        // the last build are published now.
+        let show_warnings = {
+            let config = ctx.config.lock().unwrap();
+            if !config.clear_diagnostics_on_close {
+                return Ok(());
+            }
+            config.show_warnings
+        };
+        let build_results = ctx.previous_build_results.lock().unwrap();
+        emit_file_notifications(&build_results, &file_path, show_warnings, &out);
+        Ok(())
+    }
+}
+
+/// Notification from the client that the given text document has been
+/// closed. Any unsaved changes are discarded, so its contents on disk are used
+/// again.
+#[derive(Debug)]
+pub struct DidClose;
+
+impl<'a> Action<'a> for DidClose {
+    type Params = DidCloseTextDocumentParams;
+    const METHOD: &'static str = "textDocument/didClose";
+
+    fn new(_: &'a mut LsState) -> Self {
+        DidClose
+    }
+}
+
+impl<'a> NotificationAction<'a> for DidClose {
+    fn handle<O: Output>(&mut self, params: Self::Params, ctx: &mut ActionContext, out: O) -> Result<(), ()> {
+        trace!("on_close: {:?}", params.text_document.uri);
+        let ctx = ctx.inited();
+        let file_path = parse_file_path!(&params.text_document.uri, "on_close")?;
+
+        ctx.open_files.lock().unwrap().remove(&file_path);
+
+        let differs_from_disk = match ctx.vfs.load_file(&file_path) {
+            Ok(FileContents::Text(text)) => {
+                let mut disk_text = String::new();
+                let read = File::open(&file_path).and_then(|mut f| f.read_to_string(&mut disk_text));
+                read.is_err() || disk_text != text
+            }
+            _ => false,
+        };
+        if let Err(e) = ctx.vfs.flush_file(&file_path) {
+            debug!("Couldn't flush {:?} from the VFS: {:?}", file_path, e);
+        }
+
+        if ctx.config.lock().unwrap().clear_diagnostics_on_close {
+            out.notify(NotificationMessage::new(
+                NOTIFICATION__PublishDiagnostics,
+                Some(PublishDiagnosticsParams {
+                    uri: params.text_document.uri,
+                    diagnostics: vec![],
+                }),
+            ));
+        }
+
+        // The discarded contents may have been built, in which case the
+        // project has to be rebuilt with the ones on disk.
+        if differs_from_disk && ctx.build_queue.mark_file_dirty(file_path) {
+            ctx.build_current_project(BuildPriority::Normal, out);
+        }
         Ok(())
     }
 }
@@ -118,11 +202,12 @@ impl<'a> NotificationAction<'a> for DidChange {
             }
         }).collect();
         ctx.vfs.on_changes(&changes).expect("error committing to VFS");
-        if !changes.is_empty() {
-            ctx.build_queue.mark_file_dirty(file_path, params.text_document.version)
-        }
+        // There's nothing to rebuild if the file has the same contents as
+        // when it was last built (e.g., after an undo) or it's not a part of
+        // any crate in the project.
+        let needs_build = !changes.is_empty() && ctx.build_queue.mark_file_dirty(file_path);
 
-        if !ctx.config.lock().unwrap().build_on_save {
+        if needs_build && !ctx.config.lock().unwrap().build_on_save {
             ctx.build_current_project(BuildPriority::Normal, out);
         }
         Ok(())
@@ -182,7 +267,7 @@ impl<'a> NotificationAction<'a> for DidChangeConfiguration {
             }
         };
 
-        let unstable_features = new_config.unstable_features;
+        let format_on_save = new_config.format_on_save;
 
         {
             let mut config = ctx.config.lock().unwrap();
@@ -214,27 +299,40 @@ impl<'a> NotificationAction<'a> for DidChangeConfiguration {
         // artifacts on disk).
         ctx.build_current_project(BuildPriority::Cargo, out.clone());
 
-        const RANGE_FORMATTING_ID: &'static str = "rls-range-formatting";
         // FIXME should handle the response
-        if unstable_features {
+        let mut registered = ctx.will_save_wait_until_registered.lock().unwrap();
+        if format_on_save && !*registered {
             let output = serde_json::to_string(
                 &RequestMessage::new(out.provide_id(),
                                         NOTIFICATION__RegisterCapability.to_owned(),
-                                        RegistrationParams { registrations: vec![Registration { id: RANGE_FORMATTING_ID.to_owned(), method: REQUEST__RangeFormatting.to_owned(), register_options: serde_json::Value::Null }] })
+                                        RegistrationParams { registrations: vec![will_save_wait_until_registration()] })
             ).unwrap();
             out.response(output);
-        } else {
+        } else if !format_on_save && *registered {
             let output = serde_json::to_string(
                 &RequestMessage::new(out.provide_id(),
                                         NOTIFICATION__UnregisterCapability.to_owned(),
-                                        UnregistrationParams { unregisterations: vec![Unregistration { id: RANGE_FORMATTING_ID.to_owned(), method: REQUEST__RangeFormatting.to_owned() }] })
+                                        UnregistrationParams { unregisterations: vec![Unregistration { id: WILL_SAVE_WAIT_UNTIL_ID.to_owned(), method: requests::WillSaveWaitUntil::METHOD.to_owned() }] })
             ).unwrap();
             out.response(output);
         }
+        *registered = format_on_save;
         Ok(())
     }
 }
 
+const WILL_SAVE_WAIT_UNTIL_ID: &'static str = "rls-will-save-wait-until";
+
+// `textDocument/willSaveWaitUntil` is only registered with `format_on_save`,
+// so that clients don't wait on the RLS for every save otherwise.
+fn will_save_wait_until_registration() -> Registration {
+    Registration {
+        id: WILL_SAVE_WAIT_UNTIL_ID.to_owned(),
+        method: requests::WillSaveWaitUntil::METHOD.to_owned(),
+        register_options: serde_json::Value::Null,
+    }
+}
+
 /// Notification from the client that the given text document was saved.
 #[derive(Debug)]
 pub struct DidSave;
@@ -255,6 +353,12 @@ impl<'a> NotificationAction<'a> for DidSave {
 
         ctx.vfs.file_saved(&file_path).unwrap();
 
+        // Clients which don't watch files still tell about rustfmt configs
+        // edited in the editor itself.
+        if is_fmt_config(&file_path) {
+            ctx.fmt_config.invalidate();
+        }
+
         if ctx.config.lock().unwrap().build_on_save {
             ctx.build_current_project(BuildPriority::Normal, out);
         }
@@ -289,8 +393,32 @@ impl<'a> NotificationAction<'a> for DidChangeWatchedFiles {
         let ctx = ctx.inited();
         let file_watch = FileWatch::new(&ctx);
 
+        if params.changes.iter().any(|c| file_watch.is_fmt_config(c)) {
+            ctx.fmt_config.invalidate();
+        }
+
+        // Source files may change on disk behind the client's back (e.g. after
+        // a `git checkout` or by a code generator). Unless the client has them
+        // open, the VFS may still have their old contents cached.
+        let mut needs_build = false;
+        for change in params.changes.iter().filter(|c| file_watch.is_source(c)) {
+            let file_path = match parse_file_path!(&change.uri, "on_watched_file_change") {
+                Ok(file_path) => file_path,
+                Err(()) => continue,
+            };
+            if ctx.open_files.lock().unwrap().contains(&file_path) {
+                continue;
+            }
+            if let Err(e) = ctx.vfs.flush_file(&file_path) {
+                debug!("Couldn't flush {:?} from the VFS: {:?}", file_path, e);
+            }
+            needs_build |= ctx.build_queue.mark_file_dirty(file_path);
+        }
+
         if params.changes.iter().any(|c| file_watch.is_relevant(c)) {
             ctx.build_current_project(BuildPriority::Cargo, out);
+        } else if needs_build {
+            ctx.build_current_project(BuildPriority::Normal, out);
         }
 
         Ok(())
@@ -8,16 +8,17 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::Instant;
 
-use build::BuildResult;
+use actions::analysis_cache::AnalysisCache;
+use build::{BuildRecorder, BuildResult};
 use lsp_data::{NotificationMessage, PublishDiagnosticsParams, ls_util};
 use lsp_data::{NOTIFICATION_DIAGNOSTICS_BEGIN, NOTIFICATION_DIAGNOSTICS_END};
 use server::Output;
-use CRATE_BLACKLIST;
 use Span;
 
 use analysis::AnalysisHost;
@@ -32,15 +33,21 @@ pub type BuildResults = HashMap<PathBuf, Vec<(Diagnostic, Vec<Suggestion>)>>;
 
 pub struct PostBuildHandler<O: Output> {
     pub analysis: Arc<AnalysisHost>,
+    pub analysis_cache: AnalysisCache,
+    pub build_analysis_loaded: Arc<Mutex<bool>>,
     pub previous_build_results: Arc<Mutex<BuildResults>>,
     pub project_path: PathBuf,
     pub out: O,
     pub show_warnings: bool,
-    pub use_black_list: bool,
+    /// Files the client has open, if diagnostics are only published for
+    /// these (see `clear_diagnostics_on_close`).
+    pub open_files: Option<Arc<Mutex<HashSet<PathBuf>>>>,
+    pub crate_blacklist: Vec<String>,
+    pub build_telemetry: bool,
 }
 
 impl<O: Output> PostBuildHandler<O> {
-    pub fn handle(self, result: BuildResult) {
+    pub fn handle(self, result: BuildResult, stats: BuildRecorder) {
         // We use `rustDocument` document here since these notifications are
         // custom to the RLS and not part of the LS protocol.
         self.out.notify(NotificationMessage::new(
@@ -56,18 +63,32 @@ impl<O: Output> PostBuildHandler<O> {
 
                     self.handle_messages(messages);
 
+                    // Only a build running Cargo has the analysis of every
+                    // primary crate, so the cache is only updated then (and
+                    // before the analysis is moved into the host).
+                    if stats.ran_cargo() && !new_analysis.is_empty() {
+                        self.analysis_cache.store(&new_analysis);
+                    }
+
                     // Handle the analysis data.
                     debug!("reload analysis: {:?}", self.project_path);
-                    if new_analysis.is_empty() {
-                        self.reload_analysis_from_disk();
-                    } else {
-                        self.reload_analysis_from_memory(new_analysis);
+                    let start = Instant::now();
+                    {
+                        let mut build_analysis_loaded = self.build_analysis_loaded.lock().unwrap();
+                        if new_analysis.is_empty() {
+                            self.reload_analysis_from_disk();
+                        } else {
+                            self.reload_analysis_from_memory(new_analysis);
+                        }
+                        *build_analysis_loaded = true;
                     }
+                    stats.analysis_reloaded(start.elapsed());
 
                     self.out.notify(NotificationMessage::new(
                         NOTIFICATION_DIAGNOSTICS_END,
                         None,
                     ));
+                    self.send_telemetry(&stats);
                 });
             }
             BuildResult::Squashed => {
@@ -76,6 +97,7 @@ impl<O: Output> PostBuildHandler<O> {
                     NOTIFICATION_DIAGNOSTICS_END,
                     None,
                 ));
+                self.send_telemetry(&stats);
             },
             BuildResult::Err => {
                 trace!("build - Error");
@@ -83,10 +105,20 @@ impl<O: Output> PostBuildHandler<O> {
                     NOTIFICATION_DIAGNOSTICS_END,
                     None,
                 ));
+                self.send_telemetry(&stats);
             },
         }
     }
 
+    fn send_telemetry(&self, stats: &BuildRecorder) {
+        if !self.build_telemetry {
+            return;
+        }
+        if let Some(stats) = stats.stats() {
+            self.out.telemetry_event(&stats);
+        }
+    }
+
     fn handle_messages(&self, messages: Vec<String>) {
         // These notifications will include empty sets of errors for files
         // which had errors, but now don't. This instructs the IDE to clear
@@ -100,29 +132,36 @@ impl<O: Output> PostBuildHandler<O> {
 
         for msg in &messages {
             if let Some(FileDiagnostic { file_path, diagnostic, suggestions }) = parse_diagnostics(msg) {
-                results.entry(file_path).or_insert_with(Vec::new).push((diagnostic, suggestions));
+                let diagnostics = results.entry(file_path).or_insert_with(Vec::new);
+                // The same file can be compiled as part of multiple crate
+                // targets, so only report each diagnostic once.
+                if !diagnostics.iter().any(|&(ref d, _)| *d == diagnostic) {
+                    diagnostics.push((diagnostic, suggestions));
+                }
             }
         }
 
-        emit_notifications(&results, self.show_warnings, &self.out);
+        match self.open_files {
+            Some(ref open_files) => {
+                let open_files = open_files.lock().unwrap();
+                emit_notifications(&results, self.show_warnings, Some(&open_files), &self.out);
+            }
+            None => emit_notifications(&results, self.show_warnings, None, &self.out),
+        }
     }
 
     fn reload_analysis_from_disk(&self) {
         let cwd = ::std::env::current_dir().unwrap();
-        if self.use_black_list {
-            self.analysis.reload_with_blacklist(&self.project_path, &cwd, &CRATE_BLACKLIST).unwrap();
-        } else {
-            self.analysis.reload(&self.project_path, &cwd).unwrap();
-        }
+        self.analysis.reload_with_blacklist(&self.project_path, &cwd, &self.blacklist()).unwrap();
     }
 
     fn reload_analysis_from_memory(&self, analysis: Vec<Analysis>) {
         let cwd = ::std::env::current_dir().unwrap();
-        if self.use_black_list {
-            self.analysis.reload_from_analysis(analysis, &self.project_path, &cwd, &CRATE_BLACKLIST).unwrap();
-        } else {
-            self.analysis.reload_from_analysis(analysis, &self.project_path, &cwd, &[]).unwrap();
-        }
+        self.analysis.reload_from_analysis(analysis, &self.project_path, &cwd, &self.blacklist()).unwrap();
+    }
+
+    fn blacklist(&self) -> Vec<&str> {
+        self.crate_blacklist.iter().map(|name| &name[..]).collect()
     }
 }
 
@@ -147,6 +186,10 @@ struct CompilerMessage {
     level: String,
     spans: Vec<DiagnosticSpan>,
     children: Vec<CompilerMessage>,
+    /// The `cfg` the message was exclusively emitted under, if the RLS built
+    /// more than one configuration of the crate.
+    #[serde(default)]
+    rls_cfg: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -178,7 +221,10 @@ fn parse_diagnostics(message: &str) -> Option<FileDiagnostic> {
             Some(c) => c.code.clone(),
             None => String::new(),
         })),
-        source: Some("rustc".into()),
+        source: Some(match message.rls_cfg {
+            Some(ref cfg) => format!("rustc (cfg({}))", cfg),
+            None => "rustc".into(),
+        }),
         message: message.message,
     };
 
@@ -230,27 +276,85 @@ fn primary_span(message: &CompilerMessage) -> Span {
 fn emit_notifications<O: Output>(
     build_results: &BuildResults,
     show_warnings: bool,
+    open_files: Option<&HashSet<PathBuf>>,
     out: &O,
 ) {
     let cwd = ::std::env::current_dir().unwrap();
 
     for (path, diagnostics) in build_results {
-        let params = PublishDiagnosticsParams {
-            uri: Url::from_file_path(cwd.join(path)).unwrap(),
-            diagnostics: diagnostics.iter()
-                .filter_map(|&(ref d, _)| {
-                    if show_warnings || d.severity != Some(DiagnosticSeverity::Warning) {
-                        Some(d.clone())
-                    } else {
-                        None
-                    }
-                })
-                .collect(),
-        };
+        let path = cwd.join(path);
+        // Diagnostics of closed files are cleared, if so configured.
+        if open_files.map_or(false, |open_files| !open_files.contains(&path)) {
+            publish_diagnostics(&path, vec![], out);
+        } else {
+            publish_diagnostics(&path, shown_diagnostics(diagnostics, show_warnings), out);
+        }
+    }
+}
 
-        out.notify(NotificationMessage::new(
-            ls_types::NOTIFICATION__PublishDiagnostics,
-            Some(params),
-        ));
+/// Publishes the diagnostics of the last build for a file, e.g. when it's
+/// opened and only open files have their diagnostics published.
+pub fn emit_file_notifications<O: Output>(
+    build_results: &BuildResults,
+    file: &Path,
+    show_warnings: bool,
+    out: &O,
+) {
+    if let Some(diagnostics) = file_diagnostics(build_results, file) {
+        publish_diagnostics(file, shown_diagnostics(diagnostics, show_warnings), out);
     }
 }
+
+/// Publishes rustfmt's errors from formatting a file, along with the
+/// diagnostics of the last build for it, which would be replaced otherwise.
+/// The errors are shown until the file's diagnostics are published again.
+pub fn emit_fmt_notifications<O: Output>(
+    build_results: &BuildResults,
+    file: &Path,
+    fmt_errors: &[Diagnostic],
+    show_warnings: bool,
+    out: &O,
+) {
+    let mut diagnostics = file_diagnostics(build_results, file)
+        .map(|diagnostics| shown_diagnostics(diagnostics, show_warnings))
+        .unwrap_or_default();
+    diagnostics.extend(fmt_errors.iter().cloned());
+    publish_diagnostics(file, diagnostics, out);
+}
+
+fn file_diagnostics<'a>(
+    build_results: &'a BuildResults,
+    file: &Path,
+) -> Option<&'a [(Diagnostic, Vec<Suggestion>)]> {
+    let cwd = ::std::env::current_dir().unwrap();
+    build_results.iter()
+        .find(|&(path, _)| cwd.join(path) == file)
+        .map(|(_, diagnostics)| &diagnostics[..])
+}
+
+fn shown_diagnostics(
+    diagnostics: &[(Diagnostic, Vec<Suggestion>)],
+    show_warnings: bool,
+) -> Vec<Diagnostic> {
+    diagnostics.iter()
+        .filter_map(|&(ref d, _)| {
+            if show_warnings || d.severity != Some(DiagnosticSeverity::Warning) {
+                Some(d.clone())
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+fn publish_diagnostics<O: Output>(path: &Path, diagnostics: Vec<Diagnostic>, out: &O) {
+    let params = PublishDiagnosticsParams {
+        uri: Url::from_file_path(path).unwrap(),
+        diagnostics: diagnostics,
+    };
+
+    out.notify(NotificationMessage::new(
+        ls_types::NOTIFICATION__PublishDiagnostics,
+        Some(params),
+    ));
+}
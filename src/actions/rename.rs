@@ -0,0 +1,370 @@
+// Copyright 2017 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Checking that a symbol can be renamed, and that the new name is valid and
+//! doesn't clash with another definition, rather than producing broken code.
+//! Renaming takes care of field init shorthands and of imports under another
+//! name (`use a::b as c;`).
+
+use std::path::Path;
+
+use analysis::{AnalysisHost, Def, DefKind};
+use span;
+use vfs::Vfs;
+use Span;
+
+// Strict and reserved keywords (of the 2015 edition), which can't be used as
+// identifiers.
+const KEYWORDS: &'static [&'static str] = &[
+    "abstract", "alignof", "as", "become", "box", "break", "const", "continue",
+    "crate", "do", "else", "enum", "extern", "false", "final", "fn", "for", "if",
+    "impl", "in", "let", "loop", "macro", "match", "mod", "move", "mut",
+    "offsetof", "override", "priv", "proc", "pub", "pure", "ref", "return",
+    "Self", "self", "sizeof", "static", "struct", "super", "trait", "true",
+    "type", "typeof", "unsafe", "unsized", "use", "virtual", "where", "while",
+    "yield",
+];
+
+/// A symbol which can be renamed.
+pub struct Target {
+    /// Its definition.
+    pub def: Def,
+    /// The name to replace: the definition's, or the one it's imported as
+    /// (`use a::b as c;`) where the rename was started on a use of that.
+    pub name: String,
+    /// Whether the name is an import's alias.
+    pub alias: bool,
+    /// All references to the name, including its definition.
+    pub refs: Vec<Span>,
+}
+
+/// Finds the symbol at `span` and checks that it can be renamed: it must be
+/// defined in the project (not in another crate), and every reference to it
+/// must be spelled out in the source, rather than created by a macro.
+/// References using an alias of the symbol keep that, unless the alias itself
+/// is renamed.
+pub fn target(analysis: &AnalysisHost, vfs: &Vfs, project_dir: &Path, span: &Span) -> Result<Target, String> {
+    let def = analysis.crate_local_id(span)
+        .and_then(|id| analysis.get_def(id))
+        .map_err(|_| "there's no symbol to rename here".to_owned())?;
+    if def.name == "self" || def.name == "Self" {
+        return Err(format!("`{}` can't be renamed", def.name));
+    }
+    if !def.span.file.starts_with(project_dir) {
+        return Err(format!("`{}` is defined outside of the project", def.name));
+    }
+
+    let refs = analysis.find_all_refs(span, true)
+        .map_err(|_| format!("couldn't find the references to `{}`", def.name))?;
+    let texts: Vec<Option<String>> = refs.iter().map(|r| vfs.load_span(r.clone()).ok()).collect();
+
+    // Locals and fields can't be imported, but may be cast with `as`.
+    let mut aliases = vec![];
+    let importable = def.kind != DefKind::Local && def.kind != DefKind::Field;
+    for (r, text) in refs.iter().zip(&texts) {
+        if importable && text.as_ref() == Some(&def.name) {
+            if let Ok(line) = vfs.load_line(&r.file, r.range.row_start) {
+                aliases.extend(alias(&line.chars().collect::<Vec<_>>(), r));
+            }
+        }
+    }
+
+    let word = vfs.load_span(span.clone()).unwrap_or_default();
+    if let Some(&(ref name, ref alias_span)) = aliases.iter().find(|&&(ref name, ref s)| *name == word && s.file == span.file) {
+        // The alias is only used in the file importing it.
+        let mut alias_refs = vec![alias_span.clone()];
+        alias_refs.extend(refs.iter()
+            .zip(&texts)
+            .filter(|&(r, text)| r.file == span.file && text.as_ref() == Some(name) && r != alias_span)
+            .map(|(r, _)| r.clone()));
+        return Ok(Target { name: name.clone(), alias: true, refs: alias_refs, def });
+    }
+
+    let mut def_refs = vec![];
+    for (r, text) in refs.into_iter().zip(texts) {
+        match text {
+            Some(ref text) if *text == def.name => def_refs.push(r),
+            Some(ref text) if aliases.iter().any(|&(ref name, ref s)| name == text && s.file == r.file) => {}
+            _ => {
+                return Err(format!("`{}` is used by a macro at {}:{}", def.name, r.file.display(),
+                                   r.range.row_start.one_indexed().0));
+            }
+        }
+    }
+
+    Ok(Target { name: def.name.clone(), alias: false, refs: def_refs, def })
+}
+
+/// The edits renaming the target to `new_name`, as the spans to replace and
+/// their replacements. Where a field and a local share their name in the
+/// shorthand form of a struct expression or pattern (`Foo { x }`), the field
+/// is spelled out (`Foo { y: x }` or `Foo { x: y }`).
+pub fn edits(analysis: &AnalysisHost, vfs: &Vfs, target: &Target, new_name: &str) -> Vec<(Span, String)> {
+    let shorthands = shorthands(analysis, target);
+    target.refs.iter().map(|r| {
+        if !shorthands.contains(r) {
+            return (r.clone(), new_name.to_owned());
+        }
+
+        let line: Vec<char> = vfs.load_line(&r.file, r.range.row_start)
+            .map(|line| line.chars().collect())
+            .unwrap_or_default();
+        let col = r.range.col_start.0 as usize;
+        let start = binding_start(&line, col);
+        let mut span = r.clone();
+        span.range.col_start = span::Column::new_zero_indexed(start as u32);
+        if target.def.kind == DefKind::Field {
+            span.range.col_end = span.range.col_start;
+            (span, format!("{}: ", new_name))
+        } else {
+            // The binding mode (e.g. `ref`) goes with the binding.
+            let mode: String = line.iter().skip(start).take(col - start).cloned().collect();
+            (span, format!("{}: {}{}", target.name, mode, new_name))
+        }
+    }).collect()
+}
+
+/// Checks that the target can be renamed to `new_name`: it must be an
+/// identifier (or a lifetime, if the target is one) and not a keyword, and it
+/// mustn't be given to another definition in the same scope already.
+pub fn check_new_name(analysis: &AnalysisHost, target: &Target, new_name: &str) -> Result<(), String> {
+    check_identifier(new_name, target.name.starts_with('\''))?;
+    if new_name == target.name {
+        return Ok(());
+    }
+
+    let others = analysis.name_defs(new_name).unwrap_or_else(|_| vec![]);
+    for other in others {
+        if namespace(other.kind) != namespace(target.def.kind) {
+            continue;
+        }
+        let clashes = match target.def.kind {
+            // An alias is in scope in the module importing it, roughly its file.
+            _ if target.alias => other.span.file == target.refs[0].file,
+            // Locals aren't scoped in the analysis, so any use of the name
+            // between the target's definition and its last use may be
+            // shadowed (or shadow the target) after renaming.
+            DefKind::Local => {
+                other.kind == DefKind::Local &&
+                    analysis.find_all_refs(&other.span, true)
+                        .unwrap_or_else(|_| vec![other.span.clone()])
+                        .iter()
+                        .any(|span| within_uses(span, target))
+            }
+            _ => match (other.parent, target.def.parent) {
+                (Some(a), Some(b)) => a == b,
+                (None, None) => other.span.file == target.def.span.file,
+                _ => false,
+            },
+        };
+        if clashes {
+            return Err(format!("`{}` is already defined at {}:{}", new_name, other.span.file.display(),
+                               other.span.range.row_start.one_indexed().0));
+        }
+    }
+    Ok(())
+}
+
+// Checks that `name` is an identifier (or lifetime) and not a keyword.
+fn check_identifier(name: &str, lifetime: bool) -> Result<(), String> {
+    let ident = if lifetime {
+        if !name.starts_with('\'') {
+            return Err(format!("`{}` isn't a lifetime", name));
+        }
+        &name[1..]
+    } else {
+        name
+    };
+
+    let mut chars = ident.chars();
+    let valid = match chars.next() {
+        Some(c) if c.is_alphabetic() || c == '_' => chars.all(|c| c.is_alphanumeric() || c == '_'),
+        _ => false,
+    };
+    if !valid || ident == "_" {
+        return Err(format!("`{}` isn't a valid identifier", name));
+    }
+    if lifetime && ident == "static" {
+        return Err("`'static` is a reserved lifetime".to_owned());
+    }
+    if KEYWORDS.contains(&ident) {
+        return Err(format!("`{}` is a keyword", ident));
+    }
+    Ok(())
+}
+
+// The namespaces of definitions, in which names have to be unique.
+#[derive(Debug, PartialEq)]
+enum Namespace {
+    Type,
+    Value,
+    Field,
+    Macro,
+}
+
+fn namespace(kind: DefKind) -> Namespace {
+    match kind {
+        DefKind::Enum |
+        DefKind::TupleVariant |
+        DefKind::StructVariant |
+        DefKind::Tuple |
+        DefKind::Struct |
+        DefKind::Union |
+        DefKind::Trait |
+        DefKind::Mod |
+        DefKind::Type |
+        DefKind::ExternType => Namespace::Type,
+        DefKind::Function |
+        DefKind::Method |
+        DefKind::Local |
+        DefKind::Static |
+        DefKind::Const => Namespace::Value,
+        DefKind::Field => Namespace::Field,
+        DefKind::Macro => Namespace::Macro,
+    }
+}
+
+// The alias a reference is imported as (`use a::b as c;`), with its span.
+fn alias(line: &[char], r: &Span) -> Option<(String, Span)> {
+    let skip_whitespace = |mut i: usize| {
+        while i < line.len() && line[i].is_whitespace() {
+            i += 1;
+        }
+        i
+    };
+
+    let i = skip_whitespace(r.range.col_end.0 as usize);
+    if i + 2 >= line.len() || line[i] != 'a' || line[i + 1] != 's' || !line[i + 2].is_whitespace() {
+        return None;
+    }
+    let start = skip_whitespace(i + 2);
+    let end = start + line[start..].iter().take_while(|&&c| is_ident_char(c)).count();
+    let alias: String = line[start..end].iter().cloned().collect();
+    if alias.is_empty() || alias == "_" {
+        return None;
+    }
+
+    let mut span = r.clone();
+    span.range.col_start = span::Column::new_zero_indexed(start as u32);
+    span.range.col_end = span::Column::new_zero_indexed(end as u32);
+    Some((alias, span))
+}
+
+// The references to the target which are references to a local (if it's a
+// field) or a field (if it's a local) of the same name as well, i.e. which
+// are field init shorthands.
+fn shorthands(analysis: &AnalysisHost, target: &Target) -> Vec<Span> {
+    let other_kind = match target.def.kind {
+        _ if target.alias => return vec![],
+        DefKind::Field => DefKind::Local,
+        DefKind::Local => DefKind::Field,
+        _ => return vec![],
+    };
+
+    analysis.name_defs(&target.name)
+        .unwrap_or_else(|_| vec![])
+        .into_iter()
+        .filter(|def| def.kind == other_kind)
+        .flat_map(|def| analysis.find_all_refs(&def.span, true).unwrap_or_else(|_| vec![]))
+        .filter(|span| target.refs.contains(span))
+        .collect()
+}
+
+// Where the binding at `col` starts, including its binding mode (`ref`, `mut`
+// or `ref mut`).
+fn binding_start(line: &[char], col: usize) -> usize {
+    if col > line.len() {
+        return col;
+    }
+    let mut start = col;
+    for keyword in &["mut", "ref"] {
+        let keyword: Vec<char> = keyword.chars().collect();
+        let mut i = start;
+        while i > 0 && line[i - 1].is_whitespace() {
+            i -= 1;
+        }
+        if i >= keyword.len() && line[i - keyword.len()..i] == keyword[..] &&
+           (i == keyword.len() || !is_ident_char(line[i - keyword.len() - 1])) {
+            start = i - keyword.len();
+        }
+    }
+    start
+}
+
+fn is_ident_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+// Whether `span` lies between the target's definition and its last use.
+fn within_uses(span: &Span, target: &Target) -> bool {
+    let def = &target.def.span;
+    let last_use = target.refs.iter()
+        .filter(|r| r.file == def.file)
+        .map(end)
+        .max()
+        .unwrap_or_else(|| end(def));
+    span.file == def.file && start(span) >= start(def) && end(span) <= last_use
+}
+
+fn start(span: &Span) -> (u32, u32) {
+    (span.range.row_start.0, span.range.col_start.0)
+}
+
+fn end(span: &Span) -> (u32, u32) {
+    (span.range.row_end.0, span.range.col_end.0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_check_identifier() {
+        assert!(check_identifier("foo_bar2", false).is_ok());
+        assert!(check_identifier("_foo", false).is_ok());
+        assert!(check_identifier("'a", true).is_ok());
+
+        assert_eq!(check_identifier("2foo", false), Err("`2foo` isn't a valid identifier".to_owned()));
+        assert_eq!(check_identifier("foo-bar", false), Err("`foo-bar` isn't a valid identifier".to_owned()));
+        assert_eq!(check_identifier("_", false), Err("`_` isn't a valid identifier".to_owned()));
+        assert_eq!(check_identifier("", false), Err("`` isn't a valid identifier".to_owned()));
+        assert_eq!(check_identifier("match", false), Err("`match` is a keyword".to_owned()));
+        assert_eq!(check_identifier("a", true), Err("`a` isn't a lifetime".to_owned()));
+        assert_eq!(check_identifier("'static", true), Err("`'static` is a reserved lifetime".to_owned()));
+    }
+
+    fn chars(s: &str) -> Vec<char> {
+        s.chars().collect()
+    }
+
+    #[test]
+    fn test_alias() {
+        let span = |col_start, col_end| {
+            Span::new(span::Row::new_zero_indexed(0), span::Row::new_zero_indexed(0),
+                      span::Column::new_zero_indexed(col_start), span::Column::new_zero_indexed(col_end),
+                      "lib.rs")
+        };
+
+        let line = chars("use foo::{bar as baz, qux};");
+        assert_eq!(alias(&line, &span(10, 13)), Some(("baz".to_owned(), span(17, 20))));
+        assert_eq!(alias(&line, &span(22, 25)), None);
+        assert_eq!(alias(&chars("use foo::Trait as _;"), &span(9, 14)), None);
+    }
+
+    #[test]
+    fn test_binding_start() {
+        let line = chars("let Foo { x, ref y, ref mut z, mut w, refx } = foo;");
+        assert_eq!(binding_start(&line, 10), 10);
+        assert_eq!(binding_start(&line, 17), 13);
+        assert_eq!(binding_start(&line, 28), 20);
+        assert_eq!(binding_start(&line, 35), 31);
+        assert_eq!(binding_start(&line, 38), 38);
+    }
+}
@@ -10,22 +10,29 @@
 
 //! Requests that the RLS can respond to.
 
-use actions::ActionContext;
+use actions::{ActionContext, InitActionContext};
+use actions::{format, rename};
+use actions::post_build::emit_fmt_notifications;
 use url::Url;
 use vfs::FileContents;
 use racer;
 use rustfmt::{Input as FmtInput, format_input};
+use rustfmt::config::Config as RustfmtConfig;
 use rustfmt::file_lines::{Range as RustfmtRange, FileLines};
 use serde_json;
 use span;
+use Span;
 use rayon;
 
 use lsp_data;
 use lsp_data::*;
-use server::{Output, Ack, Action, RequestAction, LsState};
+use build::{self, SerializedPlan};
+use server::{Output, Ack, Action, RequestAction, LsState, NoParams};
 use jsonrpc_core::types::ErrorCode;
 
 use std::collections::HashMap;
+use std::ops::Range as LineRange;
+use std::path::Path;
 use std::time::{Duration};
 use std::sync::{mpsc, Arc};
 
@@ -389,43 +396,38 @@ impl<'a> Action<'a> for Rename {
 
 impl<'a> RequestAction<'a> for Rename {
     type Response = WorkspaceEdit;
-    fn handle<O: Output>(&mut self, _id: usize, params: Self::Params, ctx: &mut ActionContext, _out: O) -> Result<Self::Response, ()> {
+    fn handle<O: Output>(&mut self, id: usize, params: Self::Params, ctx: &mut ActionContext, out: O) -> Result<Self::Response, ()> {
         let ctx = ctx.inited();
         let file_path = parse_file_path!(&params.text_document.uri, "rename")?;
         let span = ctx.convert_pos_to_span(file_path, params.position);
 
         let analysis = ctx.analysis.clone();
-        let receiver = receive_from_thread(move || {
-            macro_rules! unwrap_or_empty {
-                ($e: expr) => {
-                    match $e {
-                        Ok(e) => e,
-                        Err(_) => {
-                            return vec![];
-                        }
-                    }
-                }
-            }
-
-            let id = unwrap_or_empty!(analysis.crate_local_id(&span));
-            let def = unwrap_or_empty!(analysis.get_def(id));
-            if def.name == "self" || def.name == "Self" {
-                return vec![];
-            }
-
-            analysis.find_all_refs(&span, true).unwrap_or_else(|_| vec![])
+        let vfs = ctx.vfs.clone();
+        let project_dir = ctx.current_project.clone();
+        let new_name = params.new_name;
+        let receiver = receive_from_thread(move || -> Result<Vec<(Span, String)>, String> {
+            let target = rename::target(&analysis, &vfs, &project_dir, &span)?;
+            rename::check_new_name(&analysis, &target, &new_name)?;
+            Ok(rename::edits(&analysis, &vfs, &target, &new_name))
         });
 
-        let result = receiver.recv_timeout(Duration::from_millis(::COMPILER_TIMEOUT))
-            .unwrap_or_else(|_| vec![]);
+        let result = match receiver.recv_timeout(Duration::from_millis(::COMPILER_TIMEOUT)) {
+            Ok(Ok(edits)) => edits,
+            Ok(Err(e)) => {
+                debug!("rename refused: {}", e);
+                out.failure_message(id, ErrorCode::InvalidParams, format!("Can't rename: {}", e));
+                return Err(());
+            }
+            Err(_) => vec![],
+        };
 
         let mut edits: HashMap<Url, Vec<TextEdit>> = HashMap::new();
 
-        for item in result.iter() {
-            let loc = ls_util::rls_to_location(item);
+        for (span, new_text) in result {
+            let loc = ls_util::rls_to_location(&span);
             edits.entry(loc.uri).or_insert_with(Vec::new).push(TextEdit {
                 range: loc.range,
-                new_text: params.new_name.clone(),
+                new_text,
             });
         }
 
@@ -433,6 +435,46 @@ impl<'a> RequestAction<'a> for Rename {
     }
 }
 
+/// Check that the symbol at the given position can be renamed, before the
+/// user is asked for the new name, and return the range of the name.
+pub struct PrepareRename;
+
+impl<'a> Action<'a> for PrepareRename {
+    type Params = TextDocumentPositionParams;
+    const METHOD: &'static str = "textDocument/prepareRename";
+
+    fn new(_: &'a mut LsState) -> Self {
+        PrepareRename
+    }
+}
+
+impl<'a> RequestAction<'a> for PrepareRename {
+    type Response = Option<Range>;
+    fn handle<O: Output>(&mut self, id: usize, params: Self::Params, ctx: &mut ActionContext, out: O) -> Result<Self::Response, ()> {
+        let ctx = ctx.inited();
+        let file_path = parse_file_path!(&params.text_document.uri, "prepare_rename")?;
+        let span = ctx.convert_pos_to_span(file_path, params.position);
+        let range = ls_util::rls_to_range(span.range);
+
+        let analysis = ctx.analysis.clone();
+        let vfs = ctx.vfs.clone();
+        let project_dir = ctx.current_project.clone();
+        let receiver = receive_from_thread(move || {
+            rename::target(&analysis, &vfs, &project_dir, &span).map(|_| ())
+        });
+
+        match receiver.recv_timeout(Duration::from_millis(::COMPILER_TIMEOUT)) {
+            Ok(Ok(())) => Ok(Some(range)),
+            Ok(Err(e)) => {
+                debug!("rename refused: {}", e);
+                out.failure_message(id, ErrorCode::InvalidParams, format!("Can't rename: {}", e));
+                Err(())
+            }
+            Err(_) => Ok(None),
+        }
+    }
+}
+
 /// Turn wildcard style glob imports (`use foo::*`) into an import of each item
 /// that is actually used (`use foo::{Bar, Quux}`).
 pub struct Deglob;
@@ -648,7 +690,7 @@ impl<'a> Action<'a> for Formatting {
 }
 
 impl<'a> RequestAction<'a> for Formatting {
-    type Response = [TextEdit; 1];
+    type Response = Vec<TextEdit>;
     fn handle<O: Output>(&mut self, id: usize, params: Self::Params, ctx: &mut ActionContext, out: O) -> Result<Self::Response, ()> {
         reformat(id, params.text_document, None, &params.options, ctx, out)
     }
@@ -667,43 +709,67 @@ impl<'a> Action<'a> for RangeFormatting {
 }
 
 impl<'a> RequestAction<'a> for RangeFormatting {
-    type Response = [TextEdit; 1];
+    type Response = Vec<TextEdit>;
     fn handle<O: Output>(&mut self, id: usize, params: Self::Params, ctx: &mut ActionContext, out: O) -> Result<Self::Response, ()> {
         reformat(id, params.text_document, Some(params.range), &params.options, ctx, out)
     }
 }
 
-fn reformat<O: Output>(id: usize, doc: TextDocumentIdentifier, selection: Option<Range>, opts: &FormattingOptions, ctx: &mut ActionContext, out: O) -> Result<[TextEdit; 1], ()> {
+fn reformat<O: Output>(id: usize, doc: TextDocumentIdentifier, selection: Option<Range>, opts: &FormattingOptions, ctx: &mut ActionContext, out: O) -> Result<Vec<TextEdit>, ()> {
     trace!("Reformat: {} {:?} {:?} {} {}", id, doc, selection, opts.tab_size, opts.insert_spaces);
     let ctx = ctx.inited();
     let path = parse_file_path!(&doc.uri, "reformat")?;
 
-    let input = match ctx.vfs.load_file(&path) {
-        Ok(FileContents::Text(s)) => FmtInput::Text(s),
-        Ok(_) => {
-            debug!("Reformat failed, found binary file");
-            out.failure_message(id, ErrorCode::InternalError, "Reformat failed to complete successfully");
-            return Err(());
-        }
+    let lines = selection.map(format::selected_lines);
+    let result = match rustfmt(ctx, &path, lines.clone(), Some(opts), &out) {
+        Ok(result) => result,
         Err(e) => {
-            debug!("Reformat failed: {:?}", e);
+            debug!("Reformat failed: {}", e);
             out.failure_message(id, ErrorCode::InternalError, "Reformat failed to complete successfully");
             return Err(());
         }
     };
-
-    let range_whole_file = ls_util::range_from_vfs_file(&ctx.vfs, &path);
-    let mut config = ctx.fmt_config.get_rustfmt_config().clone();
-    if !config.was_set().hard_tabs() {
-        config.set().hard_tabs(!opts.insert_spaces);
-    }
-    if !config.was_set().tab_spaces() {
-        config.set().tab_spaces(opts.tab_size as usize);
+    publish_fmt_errors(ctx, &path, &result.errors, &out);
+
+    match result.formatted {
+        // Note that we don't need to update the VFS, the client
+        // echos back the change to us.
+        Some(formatted) => Ok(match lines {
+            Some(lines) => format::range_text_edits(&result.text, &formatted, lines),
+            None => format::text_edits(&result.text, &formatted),
+        }),
+        None => {
+            debug!("Reformat failed: {:?}", result.errors);
+            out.failure_message(id, ErrorCode::InternalError, "Reformat failed to complete successfully");
+            Err(())
+        }
     }
+}
+
+// The result of formatting a file with rustfmt.
+struct Formatted {
+    // The current text of the file.
+    text: String,
+    // The formatted text, unless rustfmt couldn't produce it (e.g., because
+    // the file doesn't parse).
+    formatted: Option<String>,
+    // rustfmt's errors, as diagnostics of the file.
+    errors: Vec<Diagnostic>,
+}
+
+// Formats the file (or only the given lines of it) with rustfmt. Fails only
+// if rustfmt can't be run at all.
+fn rustfmt<O: Output>(ctx: &InitActionContext, path: &Path, lines: Option<LineRange<usize>>, opts: Option<&FormattingOptions>, out: &O) -> Result<Formatted, String> {
+    let text = match ctx.vfs.load_file(path) {
+        Ok(FileContents::Text(s)) => s,
+        Ok(_) => return Err("found binary file".to_owned()),
+        Err(e) => return Err(format!("{:?}", e)),
+    };
 
-    if let Some(r) = selection {
-        let range_of_rls = ls_util::range_to_rls(r).one_indexed();
-        let range = RustfmtRange::new(range_of_rls.row_start.0 as usize, range_of_rls.row_end.0 as usize);
+    let mut config = rustfmt_config(ctx, path, opts, out);
+    if let Some(lines) = lines {
+        // rustfmt's line ranges are one-indexed and inclusive.
+        let range = RustfmtRange::new(lines.start + 1, lines.end);
         let mut ranges = HashMap::new();
         ranges.insert("stdin".to_owned(), vec![range]);
         let file_lines = FileLines::from_ranges(ranges);
@@ -711,31 +777,201 @@ fn reformat<O: Output>(id: usize, doc: TextDocumentIdentifier, selection: Option
     };
 
     let mut buf = Vec::<u8>::new();
-    match format_input(input, &config, Some(&mut buf)) {
-        Ok((summary, ..)) => {
+    match format_input(FmtInput::Text(text.clone()), &config, Some(&mut buf)) {
+        Ok((summary, _, report)) => {
             // format_input returns Ok even if there are any errors, i.e., parsing errors.
-            if summary.has_no_errors() {
-                // Note that we don't need to update the VFS, the client
-                // echos back the change to us.
-                let text = String::from_utf8(buf).unwrap();
-
-                // If Rustfmt returns range of text that changed,
-                // we will be able to pass only range of changed text to the client.
-                Ok([TextEdit {
-                    range: range_whole_file,
-                    new_text: text,
-                }])
+            // Code it can't format (e.g., because of long lines) is left as is.
+            // Parsing errors aren't in the report, the compiler reports these
+            // (with their spans) instead.
+            let formatted = if summary.has_parsing_errors() || summary.has_operational_errors() {
+                None
             } else {
-                debug!("reformat: format_input failed: has errors, summary = {:?}", summary);
+                Some(String::from_utf8(buf).unwrap())
+            };
+            let errors = fmt_errors(&report.to_string(), formatted.as_ref().unwrap_or(&text));
+            Ok(Formatted { text, formatted, errors })
+        }
+        Err(e) => Err(format!("{:?}", e)),
+    }
+}
 
-                out.failure_message(id, ErrorCode::InternalError, "Reformat failed to complete successfully");
-                Err(())
+// rustfmt's errors, which its report lists like `internal error: stdin:12:
+// line exceeded maximum width (maximum: 100, found: 112) (sorry)`, as
+// diagnostics of the lines of `text` (the formatted text, where possible).
+// Errors, including rustfmt's internal ones, are reported as such, anything
+// else as warnings.
+fn fmt_errors(report: &str, text: &str) -> Vec<Diagnostic> {
+    const FILE_NAME: &'static str = "stdin:";
+
+    report.lines()
+        .filter_map(|error| {
+            let file = error.find(FILE_NAME)?;
+            let level = error[..file].trim();
+            let rest = &error[file + FILE_NAME.len()..];
+            let colon = rest.find(':')?;
+            let line = rest[..colon].parse::<u64>().ok()?.saturating_sub(1);
+            let line_len = text.lines().nth(line as usize).map_or(0, |l| l.chars().count());
+
+            Some(Diagnostic {
+                range: Range::new(Position::new(line, 0), Position::new(line, line_len as u64)),
+                severity: Some(if level.starts_with("error") || level.starts_with("internal error") {
+                    DiagnosticSeverity::Error
+                } else {
+                    DiagnosticSeverity::Warning
+                }),
+                code: None,
+                source: Some("rustfmt".to_owned()),
+                message: rest[colon + 1..].trim().to_owned(),
+            })
+        })
+        .collect()
+}
+
+// Publishes rustfmt's errors from formatting the file, or clears the ones of
+// formatting it before.
+fn publish_fmt_errors<O: Output>(ctx: &InitActionContext, path: &Path, errors: &[Diagnostic], out: &O) {
+    let mut fmt_error_files = ctx.fmt_error_files.lock().unwrap();
+    if errors.is_empty() {
+        if !fmt_error_files.remove(path) {
+            return;
+        }
+    } else {
+        fmt_error_files.insert(path.to_owned());
+    }
+
+    let show_warnings = ctx.config.lock().unwrap().show_warnings;
+    let build_results = ctx.previous_build_results.lock().unwrap();
+    emit_fmt_notifications(&build_results, path, errors, show_warnings, out);
+}
+
+// The rustfmt config of the file's directory (the user is told if it's
+// broken). Unless set in the config, indentation follows the client's
+// formatting options, if given.
+fn rustfmt_config<O: Output>(ctx: &InitActionContext, path: &Path, opts: Option<&FormattingOptions>, out: &O) -> RustfmtConfig {
+    let (fmt_config, error) = ctx.fmt_config.get(path);
+    if let Some(error) = error {
+        out.show_message(MessageType::Warning, error);
+    }
+    let mut config = fmt_config.get_rustfmt_config().clone();
+    if let Some(opts) = opts {
+        if !config.was_set().hard_tabs() {
+            config.set().hard_tabs(!opts.insert_spaces);
+        }
+        if !config.was_set().tab_spaces() {
+            config.set().tab_spaces(opts.tab_size as usize);
+        }
+    }
+    config
+}
+
+/// Format code as it's typed: the statement ended by a `;`, or the block
+/// closed by a `}` (with the statement or item it belongs to). After a new
+/// line, the code ended on the lines before is formatted and the new line
+/// is indented.
+pub struct OnTypeFormatting;
+
+impl<'a> Action<'a> for OnTypeFormatting {
+    type Params = DocumentOnTypeFormattingParams;
+    const METHOD: &'static str = "textDocument/onTypeFormatting";
+
+    fn new(_: &'a mut LsState) -> Self {
+        OnTypeFormatting
+    }
+}
+
+impl<'a> RequestAction<'a> for OnTypeFormatting {
+    type Response = Vec<TextEdit>;
+    fn handle<O: Output>(&mut self, _id: usize, params: Self::Params, ctx: &mut ActionContext, out: O) -> Result<Self::Response, ()> {
+        trace!("on_type_formatting: {:?}", params);
+        let ctx = ctx.inited();
+        let path = parse_file_path!(&params.text_document.uri, "on_type_formatting")?;
+        let text = match ctx.vfs.load_file(&path) {
+            Ok(FileContents::Text(s)) => s,
+            _ => return Ok(vec![]),
+        };
+        let typed = format::typed_code(&text, params.position);
+
+        // Code that's being typed often doesn't parse yet, which mustn't get
+        // in the way.
+        let mut edits = match typed.ended {
+            Some(lines) => {
+                match rustfmt(ctx, &path, Some(lines.clone()), Some(&params.options), &out) {
+                    Ok(Formatted { text, formatted: Some(formatted), .. }) => {
+                        format::range_text_edits(&text, &formatted, lines)
+                    }
+                    Ok(Formatted { errors, .. }) => {
+                        debug!("On type formatting failed: {:?}", errors);
+                        vec![]
+                    }
+                    Err(e) => {
+                        debug!("On type formatting failed: {}", e);
+                        vec![]
+                    }
+                }
+            }
+            None => vec![],
+        };
+
+        if params.ch == "\n" {
+            let line = params.position.line;
+            let current: String = text.lines()
+                .nth(line as usize)
+                .map(|text| text.chars().take_while(|&c| c == ' ' || c == '\t').collect())
+                .unwrap_or_default();
+            let config = rustfmt_config(ctx, &path, Some(&params.options), &out);
+            let indent = if config.hard_tabs() {
+                "\t".repeat(typed.depth)
+            } else {
+                " ".repeat(typed.depth * config.tab_spaces())
+            };
+            // The formatting may already have changed the new line.
+            let formatted = edits.iter().any(|e| {
+                e.range.end.line > line || (e.range.end.line == line && e.range.end.character > 0)
+            });
+            if indent != current && !formatted {
+                let range = Range::new(Position::new(line, 0), Position::new(line, current.chars().count() as u64));
+                edits.push(TextEdit::new(range, indent));
             }
         }
-        Err(e) => {
-            debug!("Reformat failed: {:?}", e);
-            out.failure_message(id, ErrorCode::InternalError, "Reformat failed to complete successfully");
-            Err(())
+        Ok(edits)
+    }
+}
+
+/// Request from the client for edits to apply to the given document before
+/// it's saved. If `format_on_save` is set, the document is formatted.
+pub struct WillSaveWaitUntil;
+
+impl<'a> Action<'a> for WillSaveWaitUntil {
+    type Params = WillSaveTextDocumentParams;
+    const METHOD: &'static str = "textDocument/willSaveWaitUntil";
+
+    fn new(_: &'a mut LsState) -> Self {
+        WillSaveWaitUntil
+    }
+}
+
+impl<'a> RequestAction<'a> for WillSaveWaitUntil {
+    type Response = Vec<TextEdit>;
+    fn handle<O: Output>(&mut self, _id: usize, params: Self::Params, ctx: &mut ActionContext, out: O) -> Result<Self::Response, ()> {
+        trace!("will_save_wait_until: {:?}", params);
+        let ctx = ctx.inited();
+        // Saves made automatically (e.g. after a delay) happen while typing,
+        // when reformatting would get in the way.
+        if !ctx.config.lock().unwrap().format_on_save || params.reason != TextDocumentSaveReason::Manual {
+            return Ok(vec![]);
+        }
+        let path = parse_file_path!(&params.text_document.uri, "will_save_wait_until")?;
+
+        // A failure to format mustn't prevent the document from being saved.
+        match rustfmt(ctx, &path, None, None, &out) {
+            Ok(result) => {
+                publish_fmt_errors(ctx, &path, &result.errors, &out);
+                Ok(result.formatted.map_or(vec![], |formatted| format::text_edits(&result.text, &formatted)))
+            }
+            Err(e) => {
+                debug!("Formatting on save failed: {}", e);
+                Ok(vec![])
+            }
         }
     }
 }
@@ -765,6 +1001,48 @@ impl<'a> RequestAction<'a> for ResolveCompletion {
     }
 }
 
+/// Describe the cached build plan (units, their dependencies and compiler
+/// invocations), e.g. to find out why a crate is rebuilt or with which flags.
+pub struct BuildPlan;
+
+impl<'a> Action<'a> for BuildPlan {
+    type Params = NoParams;
+    const METHOD: &'static str = "rls/buildPlan";
+
+    fn new(_: &'a mut LsState) -> Self {
+        BuildPlan
+    }
+}
+
+impl<'a> RequestAction<'a> for BuildPlan {
+    type Response = SerializedPlan;
+    fn handle<O: Output>(&mut self, _id: usize, _params: Self::Params, ctx: &mut ActionContext, _out: O) -> Result<Self::Response, ()> {
+        let ctx = ctx.inited();
+        Ok(ctx.build_queue.build_plan())
+    }
+}
+
+/// Report the timing statistics of the most recent builds, e.g. to find out
+/// why builds feel slow.
+pub struct BuildStats;
+
+impl<'a> Action<'a> for BuildStats {
+    type Params = NoParams;
+    const METHOD: &'static str = "rls/buildStats";
+
+    fn new(_: &'a mut LsState) -> Self {
+        BuildStats
+    }
+}
+
+impl<'a> RequestAction<'a> for BuildStats {
+    type Response = Vec<build::BuildStats>;
+    fn handle<O: Output>(&mut self, _id: usize, _params: Self::Params, ctx: &mut ActionContext, _out: O) -> Result<Self::Response, ()> {
+        let ctx = ctx.inited();
+        Ok(ctx.build_queue.build_stats())
+    }
+}
+
 
 fn racer_coord(line: span::Row<span::OneIndexed>,
                column: span::Column<span::ZeroIndexed>)
@@ -814,3 +1092,33 @@ pub fn receive_from_thread<T, F>(work_fn: F) -> mpsc::Receiver<T>
     });
     receiver
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_fmt_errors() {
+        let report = "internal error: stdin:2: line exceeded maximum width (maximum: 100, found: 117) (sorry)\n\
+                      warning: stdin:3: found TODO\n\
+                      Rustfmt failed at stdin: not a line of an error\n";
+        let text = "fn main() {\n    let x = 1;\n    // TODO\n}\n";
+
+        let errors = fmt_errors(report, text);
+        assert_eq!(errors.len(), 2);
+
+        assert_eq!(errors[0].range, Range::new(Position::new(1, 0), Position::new(1, 14)));
+        assert_eq!(errors[0].severity, Some(DiagnosticSeverity::Error));
+        assert_eq!(errors[0].source, Some("rustfmt".to_owned()));
+        assert_eq!(errors[0].message, "line exceeded maximum width (maximum: 100, found: 117) (sorry)");
+
+        assert_eq!(errors[1].range, Range::new(Position::new(2, 0), Position::new(2, 11)));
+        assert_eq!(errors[1].severity, Some(DiagnosticSeverity::Warning));
+        assert_eq!(errors[1].message, "found TODO");
+
+        // Lines past the end of the text get an empty range.
+        let errors = fmt_errors("error: stdin:10: oops\n", text);
+        assert_eq!(errors[0].range, Range::new(Position::new(9, 0), Position::new(9, 0)));
+        assert_eq!(errors[0].severity, Some(DiagnosticSeverity::Error));
+    }
+}
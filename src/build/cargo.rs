@@ -14,8 +14,9 @@ use cargo::util::{Config as CargoConfig, ProcessBuilder, homedir, important_path
 use serde_json;
 
 use data::Analysis;
-use build::{Internals, BufWriter, BuildResult, CompilationContext};
+use build::{Internals, BufWriter, BuildPlan, BuildRecorder, BuildResult, CompilationContext};
 use build::environment::{self, Environment, EnvironmentLock};
+use build::stats;
 use config::Config;
 use vfs::Vfs;
 
@@ -23,19 +24,34 @@ use std::collections::{HashMap, HashSet, BTreeMap};
 use std::env;
 use std::ffi::OsString;
 use std::fs::{read_dir, remove_file};
+use std::mem;
 use std::path::{Path};
-use std::process::Command;
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread;
+use std::time::Instant;
 
 // Runs an in-process instance of Cargo.
-pub(super) fn cargo(internals: &Internals) -> BuildResult {
-    let workspace_mode = internals.config.lock().unwrap().workspace_mode;
+pub(super) fn cargo(internals: &Internals, stats: &BuildRecorder) -> BuildResult {
+    // In these modes every primary crate target is compiled in-process during
+    // the Cargo routine, so that's where the diagnostics and analysis come from.
+    let uses_build_plan = internals.config.lock().unwrap().uses_build_plan();
 
     let compilation_cx = internals.compilation_cx.clone();
     let config = internals.config.clone();
     let vfs = internals.vfs.clone();
     let env_lock = internals.env_lock.clone();
+    let abort = internals.abort_build.clone();
+
+    // The context is replaced by the one Cargo provides, but kept if Cargo
+    // is aborted, so that the following builds can still use it.
+    let previous_cx = {
+        let mut cx = internals.compilation_cx.lock().unwrap();
+        (mem::replace(&mut cx.args, vec![]),
+         mem::replace(&mut cx.envs, HashMap::new()),
+         mem::replace(&mut cx.cfg_test_args, vec![]),
+         mem::replace(&mut cx.build_plan, BuildPlan::new()))
+    };
 
     let diagnostics = Arc::new(Mutex::new(vec![]));
     let diagnostics_clone = diagnostics.clone();
@@ -43,16 +59,35 @@ pub(super) fn cargo(internals: &Internals) -> BuildResult {
     let analysis_clone = analysis.clone();
     let out = Arc::new(Mutex::new(vec![]));
     let out_clone = out.clone();
+    let stats_clone = stats.clone();
 
     // Cargo may or may not spawn threads to run the various builds, since
     // we may be in separate threads we need to block and wait our thread.
     // However, if Cargo doesn't run a separate thread, then we'll just wait
     // forever. Therefore, we spawn an extra thread here to be safe.
-    let handle = thread::spawn(|| run_cargo(compilation_cx, config, vfs, env_lock,
-                                            diagnostics, analysis, out));
+    let start = Instant::now();
+    let handle = thread::spawn(|| run_cargo(compilation_cx, config, vfs, env_lock, abort,
+                                            diagnostics, analysis, out, stats_clone));
+    let result = handle.join().map_err(|_| "thread panicked".into()).and_then(|res| res);
+    let cargo_time = stats::millis(start.elapsed());
+    stats.update(|stats| stats.cargo_time = Some(cargo_time));
+
+    // Cargo only stops early with an error, otherwise its results are complete.
+    if result.is_err() && internals.abort_build.load(Ordering::SeqCst) {
+        trace!("cargo - aborted");
+        // Cargo didn't get to run every unit, so whatever was cached is
+        // incomplete and the previous context is restored instead.
+        let mut cx = internals.compilation_cx.lock().unwrap();
+        let (args, envs, cfg_test_args, build_plan) = previous_cx;
+        cx.args = args;
+        cx.envs = envs;
+        cx.cfg_test_args = cfg_test_args;
+        cx.build_plan = build_plan;
+        return BuildResult::Squashed;
+    }
 
-    match handle.join().map_err(|_| "thread panicked".into()).and_then(|res| res) {
-        Ok(_) if workspace_mode => {
+    match result {
+        Ok(_) if uses_build_plan => {
             let diagnostics = Arc::try_unwrap(diagnostics_clone).unwrap().into_inner().unwrap();
             let analysis = Arc::try_unwrap(analysis_clone).unwrap().into_inner().unwrap();
             BuildResult::Success(diagnostics, analysis)
@@ -70,23 +105,20 @@ fn run_cargo(compilation_cx: Arc<Mutex<CompilationContext>>,
              rls_config: Arc<Mutex<Config>>,
              vfs: Arc<Vfs>,
              env_lock: Arc<EnvironmentLock>,
+             abort: Arc<AtomicBool>,
              compiler_messages: Arc<Mutex<Vec<String>>>,
              analysis: Arc<Mutex<Vec<Analysis>>>,
-             out: Arc<Mutex<Vec<u8>>>) -> CargoResult<()> {
+             out: Arc<Mutex<Vec<u8>>>,
+             stats: BuildRecorder) -> CargoResult<()> {
     // Lock early to guarantee synchronized access to env var for the scope of Cargo routine.
     // Additionally we need to pass inner lock to RlsExecutor, since it needs to hand it down
     // during exec() callback when calling linked compiler in parallel, for which we need to
     // guarantee consistent environment variables.
     let (lock_guard, inner_lock) = env_lock.lock();
 
-    let build_dir = {
-        let mut compilation_cx = compilation_cx.lock().unwrap();
-        // Since Cargo build routine will try to regenerate the unit dep graph,
-        // we need to clear the existing dep graph.
-        compilation_cx.build_plan.clear();
-
-        compilation_cx.build_dir.as_ref().unwrap().clone()
-    };
+    // The existing dep graph has been cleared, since Cargo build routine will
+    // regenerate it.
+    let build_dir = compilation_cx.lock().unwrap().build_dir.as_ref().unwrap().clone();
     // Note that this may not be equal build_dir when inside a workspace member
     let manifest_path = important_paths::find_root_manifest_for_wd(None, &build_dir)?;
     trace!("root manifest_path: {:?}", &manifest_path);
@@ -144,9 +176,8 @@ fn run_cargo(compilation_cx: Arc<Mutex<CompilationContext>>,
         spec: spec,
         filter: CompileFilter::new(opts.lib,
                                 &opts.bin, opts.bins,
-                                // TODO: Support more crate target types
                                 &[], false, &[], false, &[], false,
-                                false),
+                                opts.all_targets),
         features: &opts.features,
         all_features: opts.all_features,
         no_default_features: opts.no_default_features,
@@ -168,8 +199,10 @@ fn run_cargo(compilation_cx: Arc<Mutex<CompilationContext>>,
                                 rls_config.clone(),
                                 inner_lock,
                                 vfs,
+                                abort,
                                 compiler_messages,
-                                analysis);
+                                analysis,
+                                stats);
 
     compile_with_exec(&ws, &compile_opts, Arc::new(exec))?;
 
@@ -188,13 +221,25 @@ struct RlsExecutor {
     /// env var access during underlying `rustc()` calls during parallel `exec()` callback threads.
     env_lock: environment::InnerLock,
     vfs: Arc<Vfs>,
+    /// Set when the build should be stopped, in which case no further units
+    /// are compiled.
+    abort: Arc<AtomicBool>,
     analysis: Arc<Mutex<Vec<Analysis>>>,
     workspace_mode: bool,
     /// Packages which are directly a member of the workspace, for which
     /// analysis and diagnostics will be provided
     member_packages: Mutex<HashSet<PackageId>>,
+    /// Names of the dependency packages which are also considered primary,
+    /// as configured by `full_analysis_crates`.
+    full_analysis_crates: HashSet<String>,
+    /// Whether primary crates are compiled in-process and cached in the
+    /// build plan, e.g. because every crate target of the current package is
+    /// built with `all_targets` (see `Config::uses_build_plan`).
+    uses_build_plan: bool,
     /// JSON compiler messages emitted for each primary compiled crate
     compiler_messages: Arc<Mutex<Vec<String>>>,
+    /// Records how long compiling each primary crate took.
+    stats: BuildRecorder,
 }
 
 impl RlsExecutor {
@@ -203,10 +248,17 @@ impl RlsExecutor {
            config: Arc<Mutex<Config>>,
            env_lock: environment::InnerLock,
            vfs: Arc<Vfs>,
+           abort: Arc<AtomicBool>,
            compiler_messages: Arc<Mutex<Vec<String>>>,
-           analysis: Arc<Mutex<Vec<Analysis>>>)
+           analysis: Arc<Mutex<Vec<Analysis>>>,
+           stats: BuildRecorder)
     -> RlsExecutor {
-        let workspace_mode = config.lock().unwrap().workspace_mode;
+        let (workspace_mode, full_analysis_crates, uses_build_plan) = {
+            let config = config.lock().unwrap();
+            (config.workspace_mode,
+             config.full_analysis_crates.iter().cloned().collect(),
+             config.uses_build_plan())
+        };
         let (cur_package_id, member_packages) = if workspace_mode {
             let member_packages = ws.members()
                                     .map(|x| x.package_id().clone())
@@ -225,16 +277,24 @@ impl RlsExecutor {
             config,
             env_lock,
             vfs,
+            abort,
             analysis,
             workspace_mode,
             member_packages: Mutex::new(member_packages),
+            full_analysis_crates,
+            uses_build_plan,
             compiler_messages,
+            stats,
         }
     }
 
     /// Returns whether a given package is a primary one (every member of the
-    /// workspace is considered as such).
+    /// workspace and every package in `full_analysis_crates` is considered as
+    /// such).
     fn is_primary_crate(&self, id: &PackageId) -> bool {
+        if self.full_analysis_crates.contains(&*id.name()) {
+            return true;
+        }
         if self.workspace_mode {
             self.member_packages.lock().unwrap().contains(id)
         } else {
@@ -242,6 +302,13 @@ impl RlsExecutor {
             id == cur_package_id.as_ref().expect("Executor has not been initialized")
         }
     }
+
+    /// Returns whether the primary crates are compiled in-process during the
+    /// Cargo routine and their compiler invocations cached in the build plan,
+    /// rather than only caching args/envs of a single crate target.
+    fn uses_build_plan(&self) -> bool {
+        self.uses_build_plan
+    }
 }
 
 impl Executor for RlsExecutor {
@@ -274,6 +341,11 @@ impl Executor for RlsExecutor {
     }
 
     fn exec(&self, cargo_cmd: ProcessBuilder, id: &PackageId, target: &Target) -> CargoResult<()> {
+        // Don't start compiling anything else if the build was aborted.
+        if self.abort.load(Ordering::SeqCst) {
+            return Err("build aborted by the RLS".into());
+        }
+
         // Delete any stale data. We try and remove any json files with
         // the same crate name as Cargo would emit. This includes files
         // with the same crate name but different hashes, e.g., those
@@ -309,8 +381,8 @@ impl Executor for RlsExecutor {
         //        later in-process execution of the compiler
         let mut cmd = cargo_cmd.clone();
         let rls_executable = env::args().next().unwrap();
-        let sysroot = current_sysroot()
-                        .expect("need to specify SYSROOT env var or use rustup or multirust");
+        // Without a known sysroot, rustc falls back to its own.
+        let sysroot = self.compilation_cx.lock().unwrap().sysroot.clone();
 
         cmd.program(env::var("RUSTC").unwrap_or(rls_executable));
         cmd.env(::RUSTC_SHIM_ENV_VAR_NAME, "1");
@@ -319,6 +391,9 @@ impl Executor for RlsExecutor {
         // args/envs generated by cargo so we can run only rustc later ourselves
         // Currently we don't cache nor modify build script args
         let is_build_script = *target.kind() == TargetKind::CustomBuild;
+        // Test, bench and other harness targets (built with `all_targets`)
+        // are compiled by Cargo with `--test` and have no crate-type
+        let is_test_harness = cargo_args.iter().any(|a| a == "--test");
         if !self.is_primary_crate(id) || is_build_script {
             let build_script_notice = if is_build_script {
                 " (build script)"
@@ -327,7 +402,8 @@ impl Executor for RlsExecutor {
             };
             trace!("rustc not intercepted - {}{}", id.name(), build_script_notice);
 
-            if ::CRATE_BLACKLIST.contains(&&*crate_name) {
+            let is_blacklisted = self.config.lock().unwrap().crate_blacklist().contains(&crate_name);
+            if is_blacklisted {
                 // By running the original command (rather than using our shim), we
                 // avoid producing save-analysis data.
                 trace!("crate is blacklisted");
@@ -339,8 +415,10 @@ impl Executor for RlsExecutor {
             let save_config = serde_json::to_string(&save_config)?;
             cmd.env("RUST_SAVE_ANALYSIS_CONFIG", &OsString::from(save_config));
 
-            cmd.arg("--sysroot");
-            cmd.arg(&sysroot);
+            if let Some(ref sysroot) = sysroot {
+                cmd.arg("--sysroot");
+                cmd.arg(sysroot);
+            }
             return cmd.exec();
         }
 
@@ -348,6 +426,7 @@ impl Executor for RlsExecutor {
 
         let mut args: Vec<_> =
             cargo_args.iter().map(|a| a.clone().into_string().unwrap()).collect();
+        let mut cfg_test_args = vec![];
 
         {
             let config = self.config.lock().unwrap();
@@ -355,20 +434,39 @@ impl Executor for RlsExecutor {
             // Because we only try to emulate `cargo test` using `cargo check`, so for now
             // assume crate_type arg (i.e. in `cargo test` it isn't specified for --test targets)
             // and build test harness only for final crate type
-            let crate_type = crate_type.expect("no crate-type in rustc command line");
+            let crate_type = match crate_type {
+                Some(crate_type) => crate_type,
+                None if is_test_harness => String::new(),
+                None => panic!("no crate-type in rustc command line"),
+            };
             let build_lib = *config.build_lib.as_ref();
-            let is_final_crate_type = crate_type == "bin" || (crate_type == "lib" && build_lib);
-
-            if config.cfg_test {
-                // FIXME(#351) allow passing --test to lib crate-type when building a dependency
-                if is_final_crate_type {
-                    args.push("--test".to_owned());
-                } else {
-                    args.push("--cfg".to_owned());
-                    args.push("test".to_owned());
-                }
+            // Fully analysed dependencies have dependents expecting their
+            // metadata, so these are never built as a test harness
+            let is_dependency = config.full_analysis_crates.iter().any(|name| name == &*id.name());
+            let is_final_crate_type = is_test_harness || crate_type == "bin" ||
+                (crate_type == "lib" && build_lib && !is_dependency);
+
+            // FIXME(#351) allow passing --test to lib crate-type when building a dependency
+            let test_args = if is_final_crate_type {
+                vec!["--test".to_owned()]
+            } else {
+                vec!["--cfg".to_owned(), "test".to_owned()]
+            };
+            if config.cfg_test_both && !self.uses_build_plan() {
+                // Both configurations are built by us later on, so we only
+                // remember what's needed to build the `cfg(test)` one. A
+                // distinct disambiguator lets its analysis data be loaded
+                // next to the non-test one, rather than replacing it.
+                cfg_test_args = test_args;
+                cfg_test_args.push("-C".to_owned());
+                cfg_test_args.push("metadata=rls-cfg-test".to_owned());
+            } else if config.cfg_test_both && config.all_targets {
+                // Cargo checks every crate target as a test harness as well,
+                // so both configurations are built already.
+            } else if config.cfg_test && !is_test_harness {
+                args.extend(test_args);
             }
-            if config.sysroot.is_none() {
+            if let (None, Some(sysroot)) = (config.sysroot.as_ref(), sysroot) {
                 args.push("--sysroot".to_owned());
                 args.push(sysroot);
             }
@@ -380,13 +478,14 @@ impl Executor for RlsExecutor {
             // that before we return to Cargo.
             // FIXME Don't do this. Start our build here rather than on another thread
             // so the dep-info is ready by the time we return from this callback.
-            // NB: In `workspace_mode` regular compilation is performed here (and we don't
-            // only calculate dep-info) so it should fix the problem mentioned above.
+            // NB: When using the build plan (e.g. in `workspace_mode`) regular compilation is
+            // performed here (and we don't only calculate dep-info) so it should fix
+            // the problem mentioned above.
             let modified = args.iter()
                 .map(|a| {
                     // Emitting only dep-info is possible only for final crate type, as
                     // as others may emit required metadata for dependent crate types
-                    if a.starts_with("--emit") && is_final_crate_type && !self.workspace_mode {
+                    if a.starts_with("--emit") && is_final_crate_type && !self.uses_build_plan() {
                         "--emit=dep-info"
                     } else { a }
                 })
@@ -397,7 +496,7 @@ impl Executor for RlsExecutor {
         // Cache executed command for the build plan
         {
             let mut cx = self.compilation_cx.lock().unwrap();
-            cx.build_plan.cache_compiler_job(id, target, &cmd);
+            cx.build_plan.cache_compiler_job(id, target, is_test_harness, &cmd);
         }
 
         // Prepare modified cargo-generated args/envs for future rustc calls
@@ -405,7 +504,7 @@ impl Executor for RlsExecutor {
         args.insert(0, rustc);
         let envs = cargo_cmd.get_envs().clone();
 
-        if self.workspace_mode {
+        if self.uses_build_plan() {
             let build_dir = {
                 let cx = self.compilation_cx.lock().unwrap();
                 cx.build_dir.clone().unwrap()
@@ -413,12 +512,17 @@ impl Executor for RlsExecutor {
 
             let env_lock = self.env_lock.as_facade();
 
-            match super::rustc::rustc(&self.vfs, &args, &envs, &build_dir, self.config.clone(), env_lock) {
+            let result = self.stats.time_unit(&args, || {
+                super::rustc::rustc(&self.vfs, &args, &envs, &build_dir, self.config.clone(), env_lock,
+                                    self.abort.clone())
+            });
+            match result {
                 BuildResult::Success(mut messages, mut analysis) |
                 BuildResult::Failure(mut messages, mut analysis) => {
                     self.compiler_messages.lock().unwrap().append(&mut messages);
                     self.analysis.lock().unwrap().append(&mut analysis);
                 }
+                BuildResult::Squashed => return Err("build aborted by the RLS".into()),
                 _ => {}
             }
         } else {
@@ -429,6 +533,17 @@ impl Executor for RlsExecutor {
         let mut compilation_cx = self.compilation_cx.lock().unwrap();
         compilation_cx.args = args;
         compilation_cx.envs = envs;
+        compilation_cx.cwd = cargo_cmd.get_cwd().map(|p| p.to_owned());
+        compilation_cx.cfg_test_args = cfg_test_args;
+        // When using the build plan, the unit has just been compiled by us,
+        // so its dep-info is up to date. Otherwise, the source files are read
+        // from the dep-info of our own build once it's done (see
+        // `Internals::read_source_files`).
+        if self.uses_build_plan() {
+            if let Some(build_dir) = compilation_cx.build_dir.clone() {
+                compilation_cx.build_plan.read_unit_sources(id, target, is_test_harness, &build_dir);
+            }
+        }
 
         Ok(())
     }
@@ -443,6 +558,7 @@ struct CargoOptions {
     bins: bool,
     all: bool,
     exclude: Vec<String>,
+    all_targets: bool,
     all_features: bool,
     no_default_features: bool,
     features: Vec<String>,
@@ -458,6 +574,7 @@ impl Default for CargoOptions {
             bins: false,
             all: false,
             exclude: vec![],
+            all_targets: false,
             all_features: false,
             no_default_features: false,
             features: vec![],
@@ -482,8 +599,17 @@ impl CargoOptions {
                 no_default_features: config.no_default_features,
                 .. CargoOptions::default()
             }
+        } else if config.all_targets {
+            CargoOptions {
+                all_targets: true,
+                target: config.target.clone(),
+                features: config.features.clone(),
+                all_features: config.all_features,
+                no_default_features: config.no_default_features,
+                .. CargoOptions::default()
+            }
         } else {
-            // In single-crate mode we currently support only one crate target,
+            // Unless all targets are checked, we support only one crate target,
             // and if lib is set, then we ignore bin target config
             let (lib, bin) = match *config.build_lib.as_ref() {
                 true => (true, vec![]),
@@ -569,27 +695,6 @@ fn parse_arg(args: &[OsString], arg: &str) -> Option<String> {
     None
 }
 
-fn current_sysroot() -> Option<String> {
-    let home = env::var("RUSTUP_HOME").or(env::var("MULTIRUST_HOME"));
-    let toolchain = env::var("RUSTUP_TOOLCHAIN").or(env::var("MULTIRUST_TOOLCHAIN"));
-    if let (Ok(home), Ok(toolchain)) = (home, toolchain) {
-        Some(format!("{}/toolchains/{}", home, toolchain))
-    } else {
-        let rustc_exe = env::var("RUSTC").unwrap_or("rustc".to_owned());
-        env::var("SYSROOT")
-            .map(|s| s.to_owned())
-                .ok()
-                .or_else(|| Command::new(rustc_exe)
-                    .arg("--print")
-                    .arg("sysroot")
-                    .output()
-                    .ok()
-                    .and_then(|out| String::from_utf8(out.stdout).ok())
-                    .map(|s| s.trim().to_owned()))
-    }
-}
-
-
 /// flag_str is a string of command line args for Rust. This function removes any
 /// duplicate flags.
 fn dedup_flags(flag_str: &str) -> String {
@@ -0,0 +1,150 @@
+// Copyright 2017 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Reads the dep-info files (Makefile-style dependency lists) emitted by
+//! rustc, to find out which source files a crate is compiled from.
+
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+/// Returns the source files of the crate compiled with the given rustc
+/// arguments in the `cwd` directory, as listed by the dep-info file written
+/// by the latest such compilation. Returns `None` if the compilation doesn't
+/// emit dep-info or the file couldn't be read.
+pub fn source_files(args: &[String], cwd: &Path) -> Option<Vec<PathBuf>> {
+    let path = dep_info_path(args, cwd)?;
+    let mut contents = String::new();
+    File::open(&path).and_then(|mut f| f.read_to_string(&mut contents)).ok()?;
+
+    Some(parse(&contents).into_iter().map(|file| normalize(&cwd.join(file))).collect())
+}
+
+/// Paths coming from different sources (client URIs, compiler arguments) may
+/// differ (e.g. in symlinks), so we canonicalize them, if possible, before
+/// comparing.
+pub fn normalize(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_owned())
+}
+
+// The dep-info file is written to `<out-dir>/<crate-name><extra-filename>.d`.
+fn dep_info_path(args: &[String], cwd: &Path) -> Option<PathBuf> {
+    let emit = arg_value(args, "--emit")?;
+    if !emit.split(',').any(|kind| kind == "dep-info") {
+        return None;
+    }
+    let crate_name = arg_value(args, "--crate-name")?;
+    let out_dir = arg_value(args, "--out-dir")?;
+    let extra_filename = codegen_opt_value(args, "extra-filename").unwrap_or("");
+
+    Some(cwd.join(out_dir).join(format!("{}{}.d", crate_name, extra_filename)))
+}
+
+/// Finds the value of an argument passed as either `--arg value` or `--arg=value`.
+pub fn arg_value<'a>(args: &'a [String], arg: &str) -> Option<&'a str> {
+    for (i, a) in args.iter().enumerate() {
+        if a == arg {
+            return args.get(i + 1).map(|v| &v[..]);
+        }
+        if a.starts_with(arg) && a[arg.len()..].starts_with('=') {
+            return Some(&a[arg.len() + 1..]);
+        }
+    }
+    None
+}
+
+// Finds the value of a codegen option passed as `-C opt=value` or `-Copt=value`.
+fn codegen_opt_value<'a>(args: &'a [String], opt: &str) -> Option<&'a str> {
+    let prefix = format!("{}=", opt);
+    args.iter().enumerate()
+        .filter_map(|(i, a)| {
+            if a == "-C" {
+                args.get(i + 1).map(|v| &v[..])
+            } else if a.starts_with("-C") {
+                Some(&a[2..])
+            } else {
+                None
+            }
+        })
+        .filter(|v| v.starts_with(&prefix))
+        .map(|v| &v[prefix.len()..])
+        .last()
+}
+
+// The first rule lists every source file of the crate as dependencies of the
+// first output, with spaces in paths escaped by a backslash.
+fn parse(contents: &str) -> Vec<PathBuf> {
+    let line = match contents.lines().next() {
+        Some(line) => line,
+        None => return vec![],
+    };
+    let deps = match line.find(": ") {
+        Some(i) => &line[i + 2..],
+        None => return vec![],
+    };
+
+    let mut files = vec![];
+    let mut current = String::new();
+    let mut chars = deps.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if chars.peek() == Some(&' ') => {
+                current.push(' ');
+                chars.next();
+            }
+            ' ' => {
+                if !current.is_empty() {
+                    files.push(PathBuf::from(current.clone()));
+                    current.clear();
+                }
+            }
+            c => current.push(c),
+        }
+    }
+    if !current.is_empty() {
+        files.push(PathBuf::from(current));
+    }
+    files
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let contents = "/target/debug/deps/foo-123.rmeta: src/lib.rs src/my\\ mod.rs src/bar/mod.rs\n\
+                        \n\
+                        /target/debug/deps/foo-123.d: src/lib.rs src/my\\ mod.rs src/bar/mod.rs\n\
+                        \n\
+                        src/lib.rs:\n\
+                        src/my\\ mod.rs:\n\
+                        src/bar/mod.rs:\n";
+        assert_eq!(parse(contents), vec![PathBuf::from("src/lib.rs"),
+                                         PathBuf::from("src/my mod.rs"),
+                                         PathBuf::from("src/bar/mod.rs")]);
+        assert!(parse("").is_empty());
+    }
+
+    #[test]
+    fn test_dep_info_path() {
+        let args: Vec<String> = ["rustc", "--crate-name", "foo", "src/lib.rs",
+                                 "--emit=dep-info,metadata", "-C", "metadata=123",
+                                 "-C", "extra-filename=-123", "--out-dir", "/target/debug/deps"]
+            .iter().map(|s| s.to_string()).collect();
+        assert_eq!(dep_info_path(&args, Path::new("/project")),
+                   Some(PathBuf::from("/target/debug/deps/foo-123.d")));
+
+        let args: Vec<String> = args.into_iter()
+            .map(|a| if a.starts_with("--emit") { "--emit=metadata".to_owned() } else { a })
+            .collect();
+        assert_eq!(dep_info_path(&args, Path::new("/project")), None);
+    }
+}
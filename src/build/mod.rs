@@ -11,41 +11,61 @@
 //! Running builds as-needed for the server to answer questions.
 
 pub use self::cargo::make_cargo_config;
+pub use self::rustc::run_child as run_rustc_child;
 
 use data::Analysis;
-use vfs::Vfs;
+use vfs::{FileContents, Vfs};
 use config::Config;
+use serde_json;
 
 use self::environment::EnvironmentLock;
 
 use std::boxed::FnBox;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::collections::hash_map::DefaultHasher;
 use std::ffi::OsString;
-use std::io::{self, Write};
+use std::hash::{Hash, Hasher};
+use std::fs::File;
+use std::io::{self, Read, Write};
 use std::mem;
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 mod environment;
 mod cargo;
+mod dep_info;
 mod rustc;
 mod plan;
+mod rust_project;
+mod stats;
+mod sysroot;
 
 use self::plan::{Plan as BuildPlan, WorkStatus};
 
+pub use self::plan::{SerializedJob, SerializedPlan, SerializedUnit};
+pub use self::rust_project::find_rust_project;
+use self::rust_project::LoadedProject;
+pub use self::stats::{BuildRecorder, BuildStats, UnitStats};
+pub use self::sysroot::toolchain_hash;
+
+use self::stats::BuildStatsLog;
+
 /// Manages builds.
 ///
 /// The IDE will request builds quickly (possibly on every keystroke), there is
 /// no point running every one. We also avoid running more than one build at once.
-/// We cannot cancel builds. It might be worth running builds in parallel or
-/// canceling a started build.
+/// It might be worth running builds in parallel.
 ///
-/// High priority builds are started 'straightaway' (builds cannot be interrupted).
-/// Normal builds are started after a timeout. A new build request cancels any
-/// pending build requests.
+/// High priority builds are started 'straightaway'. Normal builds are started
+/// after a timeout. A new build request cancels any pending build requests and
+/// asks a build in progress to stop, unless that's a Cargo build and the new
+/// one isn't (we'd never get the context from Cargo otherwise). Cancellation is
+/// cooperative: the running build stops at the next point where it checks for
+/// it (e.g., between compiler phases or before Cargo starts compiling another
+/// unit) and its result is reported as squashed.
 ///
 /// From the client's point of view, a build request is not guaranteed to cause
 /// a build. However, a build is guaranteed to happen and that build will begin
@@ -68,10 +88,13 @@ pub struct BuildQueue {
     // (low, high) priority builds.
     // This lock should only be held transiently.
     queued: Arc<Mutex<(Build, Build)>>,
+    // Statistics of the most recent build requests.
+    stats: BuildStatsLog,
 }
 
-/// Used when tracking modified files across different builds.
-type FileVersion = u64;
+/// Hash of a file's contents, used when tracking modified files across
+/// different builds.
+type ContentHash = u64;
 
 // Information needed to run and configure builds.
 struct Internals {
@@ -80,12 +103,21 @@ struct Internals {
     // This lock should only be held transiently.
     compilation_cx: Arc<Mutex<CompilationContext>>,
     env_lock: Arc<EnvironmentLock>,
-    /// Set of files that were modified since last build.
-    dirty_files: Arc<Mutex<HashMap<PathBuf, FileVersion>>>,
+    /// Set of files that were modified since last build, with the hashes of
+    /// their current contents.
+    dirty_files: Arc<Mutex<HashMap<PathBuf, ContentHash>>>,
+    /// Hashes of the contents of modified files, as of the last build.
+    built_files: Mutex<HashMap<PathBuf, ContentHash>>,
+    /// Source files of the crates being built, read from the dep-info of the
+    /// last build. `None` if they're not known (yet).
+    source_files: Mutex<Option<HashSet<PathBuf>>>,
     vfs: Arc<Vfs>,
     // This lock should only be held transiently.
     config: Arc<Mutex<Config>>,
     building: AtomicBool,
+    /// Set when a build in progress should be stopped as soon as possible,
+    /// because a more recent build was requested.
+    abort_build: Arc<AtomicBool>,
 }
 
 /// The result of a build request.
@@ -118,11 +150,25 @@ struct CompilationContext {
     /// args and envs are saved from Cargo and passed to rustc.
     args: Vec<String>,
     envs: HashMap<String, Option<OsString>>,
+    /// Extra args passed to rustc to additionally build the `cfg(test)`
+    /// configuration of the crate. Empty if only one configuration is built.
+    cfg_test_args: Vec<String>,
     /// The build directory is supplied by the client and passed to Cargo.
     build_dir: Option<PathBuf>,
+    /// The directory Cargo runs rustc in, which relative paths in the args
+    /// are relative to.
+    cwd: Option<PathBuf>,
+    /// Sysroot of the project's toolchain, resolved when Cargo is run.
+    sysroot: Option<String>,
+    /// JSON compiler message reported with every build, if the project's
+    /// toolchain doesn't match the compiler the RLS is linked against.
+    sysroot_mismatch: Option<String>,
     /// Build plan, which should know all the inter-package/target dependencies
     /// along with args/envs. Only contains inter-package dep-graph for now.
-    build_plan: BuildPlan
+    build_plan: BuildPlan,
+    /// For a project without Cargo, the `rust-project.json` the build plan
+    /// was filled from, which replaces the args and envs from Cargo.
+    rust_project: Option<LoadedProject>,
 }
 
 impl CompilationContext {
@@ -130,8 +176,13 @@ impl CompilationContext {
         CompilationContext {
             args: vec![],
             envs: HashMap::new(),
+            cfg_test_args: vec![],
             build_dir: None,
+            cwd: None,
+            sysroot: None,
+            sysroot_mismatch: None,
             build_plan: BuildPlan::new(),
+            rust_project: None,
         }
     }
 }
@@ -142,8 +193,8 @@ impl CompilationContext {
 /// replaced by None or Pending when appropriate. That is, Pending means something
 /// is ready and something else may or may not be being built.
 enum Build {
-    // A build is in progress.
-    InProgress,
+    // A build with the given priority is in progress.
+    InProgress(BuildPriority),
     // A build is queued.
     Pending(PendingBuild),
     // No build.
@@ -154,9 +205,10 @@ enum Build {
 struct PendingBuild {
     build_dir: PathBuf,
     priority: BuildPriority,
-    built_files: HashMap<PathBuf, FileVersion>,
+    built_files: HashMap<PathBuf, ContentHash>,
+    stats: BuildRecorder,
     // Closure to execute once the build is complete.
-    and_then: Box<FnBox(BuildResult) + Send + 'static>,
+    and_then: Box<FnBox(BuildResult, BuildRecorder) + Send + 'static>,
 }
 
 impl Build {
@@ -167,12 +219,28 @@ impl Build {
         }
     }
 
+    fn in_progress(&self) -> Option<BuildPriority> {
+        match *self {
+            Build::InProgress(priority) => Some(priority),
+            _ => None,
+        }
+    }
+
+    // True if the build is a Cargo one, either waiting or in progress.
+    fn is_cargo(&self) -> bool {
+        match *self {
+            Build::Pending(ref build) => build.priority == BuildPriority::Cargo,
+            Build::InProgress(priority) => priority == BuildPriority::Cargo,
+            Build::None => false,
+        }
+    }
+
     // True if the build is waiting and where it should be impossible for one to
     // be in progress.
     fn is_pending_fresh(&self) -> bool {
         match *self {
             Build::Pending(_) => true,
-            Build::InProgress => unreachable!(),
+            Build::InProgress(_) => unreachable!(),
             Build::None => false,
         }
     }
@@ -191,6 +259,7 @@ impl BuildQueue {
         BuildQueue {
             internals: Arc::new(Internals::new(vfs, config)),
             queued: Arc::new(Mutex::new((Build::None, Build::None))),
+            stats: BuildStatsLog::new(),
         }
     }
 
@@ -228,30 +297,36 @@ impl BuildQueue {
     ///
     /// `and_then` is a closure to run after a build has completed or been
     /// squashed.  It must return quickly and without blocking. If it has work
-    /// to do, it should spawn a thread to do it.
+    /// to do, it should spawn a thread to do it. It's also passed the recorder
+    /// of the build's statistics, to record the handling of its result.
     pub fn request_build<F>(&self, new_build_dir: &Path, mut priority: BuildPriority, and_then: F)
-        where F: FnOnce(BuildResult) + Send + 'static
+        where F: FnOnce(BuildResult, BuildRecorder) + Send + 'static
     {
         trace!("request_build {:?}", priority);
-        let needs_compilation_ctx_from_cargo = {
-            let context = self.internals.compilation_cx.lock().unwrap();
-            context.args.is_empty() && context.envs.is_empty()
-        };
-        if needs_compilation_ctx_from_cargo {
+        let needs_cargo = self.internals.needs_cargo(new_build_dir);
+
+        let queued_clone = self.queued.clone();
+        let internals_clone = self.internals.clone();
+
+        let mut queued = self.queued.lock().unwrap();
+        // Without a context, the build has to run Cargo, unless a Cargo build
+        // that's going to provide it has been requested already (in which
+        // case the build is upgraded only if that one fails).
+        if needs_cargo && !queued.0.is_cargo() && !queued.1.is_cargo() {
             priority = BuildPriority::Cargo;
         }
+        if Self::should_abort(&queued, priority) {
+            trace!("request_build - aborting build in progress");
+            self.internals.abort_build.store(true, Ordering::SeqCst);
+        }
 
         let build = PendingBuild {
             build_dir: new_build_dir.to_owned(),
             built_files: self.internals.dirty_files.lock().unwrap().clone(),
             priority,
+            stats: self.stats.record_build(priority),
             and_then: Box::new(and_then),
         };
-
-        let queued_clone = self.queued.clone();
-        let internals_clone = self.internals.clone();
-
-        let mut queued = self.queued.lock().unwrap();
         Self::push_build(&mut queued, build);
 
         // Need to spawn while holding the lock on queued so that we don't race.
@@ -264,6 +339,17 @@ impl BuildQueue {
         }
     }
 
+    // Whether a build in progress is superseded by a new build with the given
+    // priority, so should be stopped. A Cargo build is only superseded by
+    // another one, since other builds depend on the context it provides.
+    fn should_abort(queued: &(Build, Build), priority: BuildPriority) -> bool {
+        match queued.1.in_progress().or(queued.0.in_progress()) {
+            Some(BuildPriority::Cargo) => priority == BuildPriority::Cargo,
+            Some(_) => true,
+            None => false,
+        }
+    }
+
     // Takes the unlocked build queue and pushes an incoming build onto it.
     fn push_build(queued: &mut (Build, Build), mut build: PendingBuild) {
         if build.priority == BuildPriority::Normal {
@@ -292,8 +378,9 @@ impl BuildQueue {
         let mut old_build = Build::None;
         mem::swap(build, &mut old_build);
         if let Build::Pending(build) = old_build {
+            build.stats.squashed();
             let and_then = build.and_then;
-            and_then(BuildResult::Squashed);
+            and_then(BuildResult::Squashed, build.stats);
         }
     }
 
@@ -304,17 +391,25 @@ impl BuildQueue {
             // Find the next build to run, or terminate if there are no builds.
             let build = {
                 let mut queued = queued.lock().unwrap();
-                if queued.1.is_pending_fresh() {
-                    let mut build = Build::InProgress;
-                    mem::swap(&mut queued.1, &mut build);
-                    build.as_pending()
+                // Any abort request is about a previous build (we hold the
+                // queue lock, so no new request can come in meanwhile).
+                internals.abort_build.store(false, Ordering::SeqCst);
+                let slot = if queued.1.is_pending_fresh() {
+                    &mut queued.1
                 } else if queued.0.is_pending_fresh() {
-                    let mut build = Build::InProgress;
-                    mem::swap(&mut queued.0, &mut build);
-                    build.as_pending()
+                    &mut queued.0
                 } else {
                     return;
+                };
+                let mut build = mem::replace(slot, Build::None).as_pending();
+                // The Cargo build this one waited on may have failed or been
+                // aborted, so it still has to provide the context.
+                if build.priority != BuildPriority::Cargo && internals.needs_cargo(&build.build_dir) {
+                    trace!("upgrading build to Cargo priority");
+                    build.priority = BuildPriority::Cargo;
                 }
+                *slot = Build::InProgress(build.priority);
+                build
             };
 
             let and_then = build.and_then;
@@ -334,36 +429,78 @@ impl BuildQueue {
                     queued.0.is_pending() || queued.1.is_pending()
                 };
                 if interrupt {
-                    and_then(BuildResult::Squashed);
+                    build.stats.squashed();
+                    and_then(BuildResult::Squashed, build.stats);
                     continue;
                 }
             }
 
-            // Run the build.
+            // Run the build. It's squashed if it got aborted by a newer build.
+            build.stats.started(build.priority);
+            let start = Instant::now();
             let result = internals.run_build(&build.build_dir, build.priority,
-                                             &build.built_files);
-            // Assert that the build was not squashed.
+                                             &build.built_files, &build.stats);
+            let build_time = stats::millis(start.elapsed());
+            build.stats.update(|stats| stats.build_time = Some(build_time));
             if let BuildResult::Squashed = result {
-                unreachable!();
+                build.stats.squashed();
             }
-            and_then(result);
+            and_then(result, build.stats);
 
             // Remove the in-progress marker from the build queue.
             let mut queued = queued.lock().unwrap();
-            if let Build::InProgress = queued.1 {
+            if let Build::InProgress(_) = queued.1 {
                 queued.1 = Build::None;
-            } else if let Build::InProgress = queued.0 {
+            } else if let Build::InProgress(_) = queued.0 {
                 queued.0 = Build::None;
             }
         }
     }
 
-    /// Marks a given versioned file as dirty since last build. The dirty flag
-    /// will be cleared by a successful build that builds this or a more recent
-    /// version of this file.
-    pub fn mark_file_dirty(&self, file: PathBuf, version: FileVersion) {
-        trace!("Marking file as dirty: {:?} ({})", file, version);
-        self.internals.dirty_files.lock().unwrap().insert(file, version);
+    /// Marks a given file as dirty since last build, using its current
+    /// contents in the VFS. The dirty flag will be cleared by a successful
+    /// build that builds the file with these contents.
+    ///
+    /// Returns whether the project needs to be rebuilt because of the change,
+    /// that is, if the file is compiled as part of the project and its
+    /// contents differ from the ones that were last built.
+    pub fn mark_file_dirty(&self, file: PathBuf) -> bool {
+        let hash = match self.internals.vfs.load_file(&file) {
+            Ok(FileContents::Text(text)) => hash_contents(&text),
+            _ => {
+                debug!("Couldn't load file marked as dirty: {:?}", file);
+                return true;
+            }
+        };
+        // Other files (e.g. scratch files or examples that aren't built)
+        // don't affect the build.
+        if !self.internals.is_source_file(&file) {
+            trace!("Not a source file of the project: {:?}", file);
+            return false;
+        }
+        trace!("Marking file as dirty: {:?} ({})", file, hash);
+
+        let mut dirty_files = self.internals.dirty_files.lock().unwrap();
+        // A build in progress may be building other contents, so the file can
+        // be considered unchanged only if no build is running.
+        if !self.internals.building.load(Ordering::SeqCst) &&
+           self.internals.last_built_hash(&file) == Some(hash) {
+            trace!("File has the same contents as when last built: {:?}", file);
+            dirty_files.remove(&file);
+            return false;
+        }
+        dirty_files.insert(file, hash);
+        true
+    }
+
+    /// Returns a description of the cached build plan.
+    pub fn build_plan(&self) -> SerializedPlan {
+        self.internals.compilation_cx.lock().unwrap().build_plan.serialize()
+    }
+
+    /// Returns the statistics of the most recent build requests, oldest first.
+    pub fn build_stats(&self) -> Vec<BuildStats> {
+        self.stats.recent()
     }
 }
 
@@ -374,10 +511,13 @@ impl Internals {
             vfs,
             config,
             dirty_files: Arc::new(Mutex::new(HashMap::new())),
+            built_files: Mutex::new(HashMap::new()),
+            source_files: Mutex::new(None),
             // Since environment is global mutable state and we can run multiple server
             // instances, be sure to use a global lock to ensure env var consistency
             env_lock: EnvironmentLock::get(),
             building: AtomicBool::new(false),
+            abort_build: Arc::new(AtomicBool::new(false)),
         }
     }
 
@@ -386,10 +526,24 @@ impl Internals {
         &self,
         new_build_dir: &Path,
         priority: BuildPriority,
-        built_files: &HashMap<PathBuf, FileVersion>,
+        built_files: &HashMap<PathBuf, ContentHash>,
+        stats: &BuildRecorder,
     ) -> BuildResult {
         trace!("run_build, {:?} {:?}", new_build_dir, priority);
 
+        // The project (or its `rust-toolchain` file, which is watched) may
+        // have changed, so the sysroot is resolved again whenever Cargo is
+        // rerun. Builds in between use the same sysroot, so these report the
+        // same mismatch.
+        let sysroot = if priority == BuildPriority::Cargo {
+            let sysroot = sysroot::resolve(new_build_dir);
+            let used_sysroot = self.config.lock().unwrap().sysroot.clone().or_else(|| sysroot.clone());
+            let mismatch = used_sysroot.and_then(|s| sysroot::mismatch_message(&s, new_build_dir));
+            Some((sysroot, mismatch))
+        } else {
+            None
+        };
+
         // Check if the build directory changed and update it.
         {
             let mut compilation_cx = self.compilation_cx.lock().unwrap();
@@ -399,34 +553,114 @@ impl Internals {
                 (*compilation_cx).build_dir = Some(new_build_dir.to_owned());
             }
 
-            if priority == BuildPriority::Cargo {
-                // Killing these args indicates we'll do a full Cargo build.
-                compilation_cx.args = vec![];
-                compilation_cx.envs = HashMap::new();
+            if let Some((sysroot, mismatch)) = sysroot {
+                compilation_cx.sysroot = sysroot;
+                compilation_cx.sysroot_mismatch = mismatch;
             }
         }
 
-        let result = self.build();
+        let result = self.build(priority, stats);
+        // Without a matching toolchain the build fails in obscure ways, so
+        // the actual cause is reported along with it.
+        let result = match (result, self.compilation_cx.lock().unwrap().sysroot_mismatch.clone()) {
+            (BuildResult::Success(mut messages, analysis), Some(mismatch)) => {
+                messages.push(mismatch);
+                BuildResult::Success(messages, analysis)
+            }
+            (BuildResult::Failure(mut messages, analysis), Some(mismatch)) => {
+                messages.push(mismatch);
+                BuildResult::Failure(messages, analysis)
+            }
+            (result, _) => result,
+        };
         // On a successful build, clear dirty files that were successfully built
         // now. It's possible that a build was scheduled with given files, but
         // user later changed them. These should still be left as dirty (not built).
         match *&result {
             BuildResult::Success(_, _) | BuildResult::Failure(_, _) => {
-                let mut dirty_files = self.dirty_files.lock().unwrap();
-                dirty_files.retain(|file, dirty_version| {
-                    built_files.get(file)
-                    .map(|built_version| built_version < dirty_version)
-                    .unwrap_or(false)
-                });
-                trace!("Files still dirty after the build: {:?}", *dirty_files);
+                {
+                    let mut dirty_files = self.dirty_files.lock().unwrap();
+                    let mut last_built_files = self.built_files.lock().unwrap();
+                    last_built_files.extend(built_files.iter().map(|(file, hash)| (file.clone(), *hash)));
+                    dirty_files.retain(|file, dirty_hash| {
+                        last_built_files.get(file) != Some(dirty_hash)
+                    });
+                    trace!("Files still dirty after the build: {:?}", *dirty_files);
+                }
+
+                let source_files = self.read_source_files();
+                *self.source_files.lock().unwrap() = source_files;
             },
             _ => {}
         };
         result
     }
 
-    // Build the project.
-    fn build(&self) -> BuildResult {
+    // Whether a build in `build_dir` has to run Cargo to get the context to
+    // build with, as the context is missing or is for another directory.
+    fn needs_cargo(&self, build_dir: &Path) -> bool {
+        let cx = self.compilation_cx.lock().unwrap();
+        (cx.args.is_empty() && cx.envs.is_empty() && cx.rust_project.is_none()) ||
+            cx.build_dir.as_ref().map_or(true, |dir| dir != build_dir)
+    }
+
+    // The contents of the file as of the last build. Files which weren't ever
+    // modified are built as they are on disk.
+    fn last_built_hash(&self, file: &Path) -> Option<ContentHash> {
+        if let Some(hash) = self.built_files.lock().unwrap().get(file) {
+            return Some(*hash);
+        }
+        let mut contents = String::new();
+        File::open(file).and_then(|mut f| f.read_to_string(&mut contents)).ok()?;
+        Some(hash_contents(&contents))
+    }
+
+    // Whether the file is a part of any crate being built. Returns true if
+    // that's not known.
+    fn is_source_file(&self, file: &Path) -> bool {
+        match *self.source_files.lock().unwrap() {
+            Some(ref source_files) => source_files.contains(&dep_info::normalize(file)),
+            None => true,
+        }
+    }
+
+    // The `rust-project.json` describing the project, if it isn't built
+    // with Cargo.
+    fn rust_project_file(&self) -> Option<PathBuf> {
+        let cx = self.compilation_cx.lock().unwrap();
+        cx.build_dir.as_ref().and_then(|dir| find_rust_project(dir))
+    }
+
+    // When building every package in the workspace, every crate target in
+    // the package, fully analysed dependencies or a project without Cargo,
+    // there are many compiler invocations to keep track of, so we use the
+    // build plan.
+    fn uses_build_plan(&self) -> bool {
+        if self.config.lock().unwrap().uses_build_plan() {
+            return true;
+        }
+        self.rust_project_file().is_some()
+    }
+
+    fn read_source_files(&self) -> Option<HashSet<PathBuf>> {
+        let uses_build_plan = self.uses_build_plan();
+
+        let cx = self.compilation_cx.lock().unwrap();
+        let build_dir = cx.build_dir.as_ref()?;
+        if uses_build_plan {
+            cx.build_plan.source_files()
+        } else {
+            let cwd = cx.cwd.as_ref().unwrap_or(build_dir);
+            let mut files: HashSet<_> = dep_info::source_files(&cx.args, cwd)?.into_iter().collect();
+            // Modified build scripts are rerun by Cargo.
+            files.extend(cx.build_plan.build_script_files());
+            Some(files)
+        }
+    }
+
+    // Build the project. Cargo is rerun for a `Cargo` priority build, or if
+    // the context is missing.
+    fn build(&self, priority: BuildPriority, stats: &BuildRecorder) -> BuildResult {
         trace!("running build");
         // When we change build directory (presumably because the IDE is
         // changing project), we must do a cargo build of the whole project.
@@ -445,29 +679,44 @@ impl Internals {
         // disk).
 
         // Don't hold this lock when we run Cargo.
-        let needs_to_run_cargo = self.compilation_cx.lock().unwrap().args.is_empty();
-        let workspace_mode = self.config.lock().unwrap().workspace_mode;
+        let needs_to_run_cargo = priority == BuildPriority::Cargo || {
+            let modified: Vec<_> = self.dirty_files.lock().unwrap().keys().cloned().collect();
+            let cx = self.compilation_cx.lock().unwrap();
+            // Only Cargo reruns a modified build script.
+            let build_scripts = cx.build_plan.build_script_files();
+            cx.args.is_empty() || modified.iter().any(|f| build_scripts.contains(&dep_info::normalize(f)))
+        };
+
+        // Without Cargo, the plan comes from the project description, which
+        // is only read again when it changes.
+        if let Some(project_file) = self.rust_project_file() {
+            return rust_project::build(self, &project_file, stats);
+        }
 
-        if workspace_mode {
+        if self.uses_build_plan() {
             // If the build plan has already been cached, use it, unless Cargo
             // has to be specifically rerun (e.g. when build scripts changed)
-            let work = {
+            let work = if priority == BuildPriority::Cargo {
+                WorkStatus::NeedsCargo
+            } else {
                 let modified: Vec<_> = self.dirty_files.lock().unwrap()
                                            .keys().cloned().collect();
                 let cx = self.compilation_cx.lock().unwrap();
                 cx.build_plan.prepare_work(&modified)
             };
             return match work {
-                // In workspace_mode, cargo performs the full build and returns
+                // In these modes, cargo performs the full build and returns
                 // appropriate diagnostics/analysis data
-                WorkStatus::NeedsCargo => cargo::cargo(self),
-                WorkStatus::Execute(job_queue) => job_queue.execute(self),
+                WorkStatus::NeedsCargo => cargo::cargo(self, stats),
+                WorkStatus::Execute(job_queue) => job_queue.execute(self, stats),
             };
         // In single package mode Cargo needs to be run to cache args/envs for
         // future rustc calls
         } else if needs_to_run_cargo {
-            if let BuildResult::Err = cargo::cargo(self) {
-                return BuildResult::Err;
+            match cargo::cargo(self, stats) {
+                BuildResult::Err => return BuildResult::Err,
+                BuildResult::Squashed => return BuildResult::Squashed,
+                _ => {}
             }
         }
 
@@ -477,7 +726,67 @@ impl Internals {
         let envs = &compile_cx.envs;
         let build_dir = compile_cx.build_dir.as_ref().unwrap();
         let env_lock = self.env_lock.as_facade();
-        rustc::rustc(&self.vfs, args, envs, build_dir, self.config.clone(), env_lock)
+        let result = stats.time_unit(args, || {
+            rustc::rustc(&self.vfs, args, envs, build_dir, self.config.clone(), env_lock,
+                         self.abort_build.clone())
+        });
+        if compile_cx.cfg_test_args.is_empty() {
+            return result;
+        }
+
+        // Build the `cfg(test)` configuration as well and merge both results.
+        let test_args: Vec<_> = args.iter().chain(&compile_cx.cfg_test_args).cloned().collect();
+        let env_lock = self.env_lock.as_facade();
+        let test_result = stats.time_unit(&test_args, || {
+            rustc::rustc(&self.vfs, &test_args, envs, build_dir, self.config.clone(), env_lock,
+                         self.abort_build.clone())
+        });
+        merge_cfg_test_results(result, test_result)
+    }
+}
+
+/// Merges results of building a crate both without and with `cfg(test)`.
+/// Diagnostics reported by only one of the configurations are marked as such,
+/// so that the user can tell which one produced them.
+fn merge_cfg_test_results(normal: BuildResult, test: BuildResult) -> BuildResult {
+    let (normal_success, normal_messages, mut analysis) = match normal {
+        BuildResult::Success(messages, analysis) => (true, messages, analysis),
+        BuildResult::Failure(messages, analysis) => (false, messages, analysis),
+        result => return result,
+    };
+    let (test_success, test_messages, mut test_analysis) = match test {
+        BuildResult::Success(messages, analysis) => (true, messages, analysis),
+        BuildResult::Failure(messages, analysis) => (false, messages, analysis),
+        result => return result,
+    };
+
+    let mut messages: Vec<_> = normal_messages.iter().map(|msg| {
+        if test_messages.contains(msg) {
+            msg.clone()
+        } else {
+            mark_message_cfg(msg, "not(test)")
+        }
+    }).collect();
+    messages.extend(test_messages.iter()
+        .filter(|msg| !normal_messages.contains(msg))
+        .map(|msg| mark_message_cfg(msg, "test")));
+    analysis.append(&mut test_analysis);
+
+    if normal_success && test_success {
+        BuildResult::Success(messages, analysis)
+    } else {
+        BuildResult::Failure(messages, analysis)
+    }
+}
+
+/// Records in a JSON compiler message under which `cfg` it was emitted.
+fn mark_message_cfg(msg: &str, cfg: &str) -> String {
+    match serde_json::from_str(msg) {
+        Ok(serde_json::Value::Object(mut map)) => {
+            map.insert("rls_cfg".to_owned(), serde_json::Value::String(cfg.to_owned()));
+            serde_json::Value::Object(map).to_string()
+        }
+        _ => msg.to_owned(),
     }
 }
 
@@ -492,3 +801,82 @@ impl Write for BufWriter {
         self.0.lock().unwrap().flush()
     }
 }
+
+fn hash_contents(contents: &str) -> ContentHash {
+    let mut hasher = DefaultHasher::new();
+    contents.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::mpsc;
+
+    fn pending(priority: BuildPriority, log: &BuildStatsLog, squashed: mpsc::Sender<BuildPriority>) -> PendingBuild {
+        PendingBuild {
+            build_dir: PathBuf::from("/project"),
+            priority,
+            built_files: HashMap::new(),
+            stats: log.record_build(priority),
+            and_then: Box::new(move |result: BuildResult, _| {
+                if let BuildResult::Squashed = result {
+                    squashed.send(priority).unwrap();
+                }
+            }),
+        }
+    }
+
+    #[test]
+    fn test_should_abort() {
+        let in_progress = |priority| (Build::None, Build::InProgress(priority));
+        assert!(!BuildQueue::should_abort(&(Build::None, Build::None), BuildPriority::Cargo));
+
+        // Only a Cargo build supersedes a running Cargo build.
+        let queued = in_progress(BuildPriority::Cargo);
+        assert!(!BuildQueue::should_abort(&queued, BuildPriority::Normal));
+        assert!(!BuildQueue::should_abort(&queued, BuildPriority::Immediate));
+        assert!(BuildQueue::should_abort(&queued, BuildPriority::Cargo));
+
+        // Any build supersedes a running rustc build.
+        let queued = in_progress(BuildPriority::Immediate);
+        assert!(BuildQueue::should_abort(&queued, BuildPriority::Normal));
+        let queued = (Build::InProgress(BuildPriority::Normal), Build::None);
+        assert!(BuildQueue::should_abort(&queued, BuildPriority::Normal));
+        assert!(BuildQueue::should_abort(&queued, BuildPriority::Cargo));
+    }
+
+    #[test]
+    fn test_push_build() {
+        let log = BuildStatsLog::new();
+        let (sender, squashed) = mpsc::channel();
+
+        // A normal build waits behind a running Cargo build.
+        let mut queued = (Build::None, Build::InProgress(BuildPriority::Cargo));
+        BuildQueue::push_build(&mut queued, pending(BuildPriority::Normal, &log, sender.clone()));
+        assert!(queued.0.is_pending());
+        assert_eq!(queued.1.in_progress(), Some(BuildPriority::Cargo));
+
+        // Newer builds of the same priority replace it.
+        BuildQueue::push_build(&mut queued, pending(BuildPriority::Normal, &log, sender.clone()));
+        assert_eq!(squashed.try_recv(), Ok(BuildPriority::Normal));
+        assert!(squashed.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_merge_cfg_test_results() {
+        let shared = r#"{"message":"shared"}"#.to_owned();
+        let normal = BuildResult::Success(vec![shared.clone(), r#"{"message":"normal"}"#.to_owned()], vec![]);
+        let test = BuildResult::Failure(vec![shared.clone(), r#"{"message":"test"}"#.to_owned()], vec![]);
+
+        match merge_cfg_test_results(normal, test) {
+            BuildResult::Failure(messages, _) => {
+                assert_eq!(messages.len(), 3);
+                assert_eq!(messages[0], shared);
+                assert!(messages[1].contains(r#""rls_cfg":"not(test)""#));
+                assert!(messages[2].contains(r#""rls_cfg":"test""#));
+            }
+            result => panic!("unexpected build result: {:?}", result),
+        }
+    }
+}
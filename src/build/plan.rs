@@ -13,8 +13,9 @@
 //! files, to retrieve a queue of compiler calls to be invoked (including
 //! appropriate arguments and env variables).
 //! The underlying structure is a dependency graph between simplified units
-//! (package id and crate target kind), as opposed to Cargo units (package with
-//! a target info, including crate target kind, profile and host/target kind).
+//! (package id, crate target kind, crate target name and whether it's built as
+//! a test), as opposed to Cargo units (package with a target info, including
+//! crate target kind, profile and host/target kind).
 //! This will be used for a quick check recompilation and does not aim to
 //! reimplement all the intricacies of Cargo.
 //! The unit dependency graph in Cargo also distinguishes between compiling the
@@ -24,18 +25,29 @@
 //! with a same package and crate target kind (e.g. both building and running
 //! build scripts).
 
-use std::collections::{HashMap, HashSet};
+use std::cmp;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ffi::OsStr;
 use std::fmt;
-use std::path::Path;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc};
 
 use cargo::core::{PackageId, Profile, Target, TargetKind};
 use cargo::ops::{Kind, Unit, Context};
 use cargo::util::{CargoResult, ProcessBuilder};
+use rayon;
+use serde_json;
 
-use super::{BuildResult, Internals};
+use super::{BuildRecorder, BuildResult, Internals};
+use super::dep_info;
 
 /// Main key type by which `Unit`s will be distinguished in the build plan.
-pub type UnitKey = (PackageId, TargetKind);
+/// The target name is needed to tell apart crate targets of the same kind in a
+/// single package (e.g. multiple binaries or integration tests), and the test
+/// flag (`profile.test`) to tell apart a crate target built normally and as a
+/// test harness (e.g. the lib with `all_targets`).
+pub type UnitKey = (PackageId, TargetKind, String, bool);
 /// Holds the information how exactly the build will be performed for a given
 /// workspace with given, specified features.
 pub struct Plan {
@@ -47,6 +59,9 @@ pub struct Plan {
     pub rev_dep_graph: HashMap<UnitKey, HashSet<UnitKey>>,
     /// Cached compiler calls used when creating a compiler call queue.
     pub compiler_jobs: HashMap<UnitKey, ProcessBuilder>,
+    /// Source files of every unit, as listed by the dep-info emitted by its
+    /// latest compilation.
+    pub unit_sources: HashMap<UnitKey, HashSet<PathBuf>>,
 }
 
 impl Plan {
@@ -56,6 +71,7 @@ impl Plan {
             dep_graph: HashMap::new(),
             rev_dep_graph: HashMap::new(),
             compiler_jobs: HashMap::new(),
+            unit_sources: HashMap::new(),
         }
     }
 
@@ -70,11 +86,53 @@ impl Plan {
     }
 
     /// Cache a given compiler invocation in `ProcessBuilder` for a given
-    /// `PackageId` and `TargetKind` in `Target`, to be used when processing
-    /// cached build plan.
-    pub fn cache_compiler_job(&mut self, id: &PackageId, target: &Target, cmd: &ProcessBuilder) {
-        let pkg_key = (id.clone(), target.kind().clone());
-        self.compiler_jobs.insert(pkg_key, cmd.clone());
+    /// `PackageId` and `TargetKind` and name in `Target`, built as a test
+    /// or not, to be used when processing cached build plan.
+    pub fn cache_compiler_job(&mut self, id: &PackageId, target: &Target, test: bool, cmd: &ProcessBuilder) {
+        self.compiler_jobs.insert(unit_key(id, target, test), cmd.clone());
+    }
+
+    /// Reads the source files of a compiled unit from the dep-info emitted by
+    /// its cached compiler invocation, which is run in `build_dir` unless
+    /// specified otherwise.
+    pub fn read_unit_sources(&mut self, id: &PackageId, target: &Target, test: bool, build_dir: &Path) {
+        self.read_sources_for_key(unit_key(id, target, test), build_dir);
+    }
+
+    fn read_sources_for_key(&mut self, key: UnitKey, build_dir: &Path) {
+        let sources = self.compiler_jobs.get(&key).and_then(|job| {
+            let args: Vec<_> = job.get_args().iter().cloned()
+                .map(|x| x.into_string().unwrap()).collect();
+            let cwd = job.get_cwd().unwrap_or(build_dir);
+            dep_info::source_files(&args, cwd)
+        });
+        match sources {
+            Some(sources) => { self.unit_sources.insert(key, sources.into_iter().collect()); }
+            None => {
+                trace!("Couldn't read the source files of {:?}", key);
+                self.unit_sources.remove(&key);
+            }
+        }
+    }
+
+    /// Returns the source files of every unit with a cached compiler job,
+    /// along with the build scripts. Returns `None` if these aren't known for
+    /// any of the units.
+    pub fn source_files(&self) -> Option<HashSet<PathBuf>> {
+        let mut files = self.build_script_files();
+        for key in self.compiler_jobs.keys() {
+            files.extend(self.unit_sources.get(key)?.iter().cloned());
+        }
+        Some(files)
+    }
+
+    /// Returns the (normalized) root source files of the build scripts of
+    /// the primary crates.
+    pub fn build_script_files(&self) -> HashSet<PathBuf> {
+        self.units.iter()
+            .filter(|&(&(_, ref kind, _, _), _)| *kind == TargetKind::CustomBuild)
+            .map(|(_, unit)| dep_info::normalize(unit.target.src_path()))
+            .collect()
     }
 
     /// Emplace a given `Unit`, along with its `Unit` dependencies (recursively)
@@ -102,7 +160,8 @@ impl Plan {
 
         // Keep all the additional Unit information for a given unit (It's
         // worth remembering, that the units are only discriminated by a
-        // pair of (PackageId, TargetKind), so only first occurrence will be saved.
+        // (PackageId, TargetKind, target name, test) tuple, so only first
+        // occurrence will be saved.
         self.units.insert(key.clone(), unit.into());
 
         // Fetch and insert relevant unit dependencies to the forward dep graph.
@@ -134,40 +193,52 @@ impl Plan {
         Ok(())
     }
 
-    /// TODO: Improve detecting dirty crate targets for a set of dirty file paths.
-    /// This uses a lousy heuristic of checking path prefix for a given crate
-    /// target to determine whether a given unit (crate target) is dirty. This
-    /// can easily backfire, e.g. when build script is under src/. Any change
-    /// to a file under src/ would imply the build script is always dirty, so we
-    /// never do work and always offload to Cargo in such case.
-    /// Because of that, build scripts are checked separately and only other
-    /// crate targets are checked with path prefixes.
+    /// Returns the units which are dirty because of the modified files. A
+    /// unit is dirty if a modified file is one of its source files, which are
+    /// read from the dep-info emitted by rustc. A file can be a part of
+    /// several units (e.g. a module shared by a lib and a bin), in which case
+    /// all of them are dirty.
+    /// Build scripts are matched by their path, and for the units whose
+    /// source files aren't known, we fall back to finding the unit with the
+    /// most specific source directory containing the modified file. Files
+    /// belonging to no unit don't make anything dirty.
     fn fetch_dirty_units<T: AsRef<Path> + fmt::Debug>(&self, files: &[T]) -> HashSet<UnitKey> {
         let mut result = HashSet::new();
 
-        let build_scripts: HashMap<&Path, UnitKey> = self.units.iter()
-            .filter(|&(&(_, ref kind), _)| *kind == TargetKind::CustomBuild)
-            .map(|(key, ref unit)| (unit.target.src_path(), key.clone())).collect();
-        let other_targets: HashMap<UnitKey, &Path> = self.units.iter()
-            .filter(|&(&(_, ref kind), _)| *kind != TargetKind::CustomBuild)
-            .map(|(key, ref unit)| (key.clone(), unit.target.src_path().parent().unwrap())).collect();
+        let build_scripts: HashMap<PathBuf, UnitKey> = self.units.iter()
+            .filter(|&(&(_, ref kind, _, _), _)| *kind == TargetKind::CustomBuild)
+            .map(|(key, ref unit)| (dep_info::normalize(unit.target.src_path()), key.clone())).collect();
+        let unknown_targets: HashMap<UnitKey, PathBuf> = self.units.iter()
+            .filter(|&(&(_, ref kind, _, _), _)| *kind != TargetKind::CustomBuild)
+            .filter(|&(key, _)| !self.unit_sources.contains_key(key))
+            .map(|(key, ref unit)| (key.clone(), dep_info::normalize(unit.target.src_path().parent().unwrap())))
+            .collect();
 
         for modified in files {
-            if let Some(unit) = build_scripts.get(modified.as_ref()) {
+            let modified = dep_info::normalize(modified.as_ref());
+            if let Some(unit) = build_scripts.get(&modified) {
                 result.insert(unit.clone());
-            } else {
-                // Not a build script, so we associate a dirty package with a
-                // dirty file by finding longest (most specified) path prefix
-                let unit = other_targets.iter().max_by_key(|&(_, src_dir)| {
-                    modified.as_ref().components().zip(src_dir.components())
-                        .take_while(|&(a, b)| a == b)
-                        .count()
-                });
-                match unit {
-                    None => trace!("Modified file {:?} doesn't correspond to any package!", modified),
-                    Some(unit) => { result.insert(unit.0.clone()); },
-                };
+                continue;
+            }
+
+            let owners: Vec<_> = self.unit_sources.iter()
+                .filter(|&(_, sources)| sources.contains(&modified))
+                .map(|(key, _)| key.clone())
+                .collect();
+            if !owners.is_empty() {
+                result.extend(owners);
+                continue;
             }
+
+            // Associate a dirty package with a dirty file by finding the
+            // most specific source directory the file is in
+            let unit = unknown_targets.iter()
+                .filter(|&(_, src_dir)| modified.starts_with(src_dir))
+                .max_by_key(|&(_, src_dir)| src_dir.components().count());
+            match unit {
+                None => trace!("Modified file {:?} doesn't correspond to any package!", modified),
+                Some(unit) => { result.insert(unit.0.clone()); },
+            };
         }
         result
     }
@@ -175,30 +246,29 @@ impl Plan {
     /// For a given set of select dirty units, returns a set of all the
     /// dependencies that has to be rebuilt transitively.
     fn transitive_dirty_units(&self, dirties: &HashSet<UnitKey>) -> HashSet<UnitKey> {
-        let mut transitive = dirties.clone();
+        let mut transitive = HashSet::new();
         // Walk through a rev dep graph using a stack of nodes to collect
         // transitively every dirty node
         let mut to_process: Vec<_> = dirties.iter().cloned().collect();
         while let Some(top) = to_process.pop() {
-            if transitive.get(&top).is_some() { continue; }
-            else { transitive.insert(top.clone()); }
+            if !transitive.insert(top.clone()) { continue; }
 
-            // Process every dirty rev dep of the processed node
-            let dirty_rev_deps = self.rev_dep_graph.get(&top).unwrap()
-                .iter().filter(|dep| dirties.contains(dep));
-            for rev_dep in dirty_rev_deps {
+            // Every rev dep of a dirty node is dirty as well
+            for rev_dep in self.rev_dep_graph.get(&top).unwrap() {
                 to_process.push(rev_dep.clone());
             }
         }
         transitive
     }
 
-    /// Creates a dirty reverse dependency graph using a set of given dirty units.
-    fn dirty_rev_dep_graph(&self, dirties: &HashSet<UnitKey>) -> HashMap<UnitKey, HashSet<UnitKey>> {
+    /// Creates a dirty dependency graph using a set of given dirty units. Every
+    /// dirty unit is mapped to the dirty units it directly depends on, which
+    /// have to be rebuilt before it.
+    fn dirty_dep_graph(&self, dirties: &HashSet<UnitKey>) -> HashMap<UnitKey, HashSet<UnitKey>> {
         let dirties = self.transitive_dirty_units(dirties);
         trace!("transitive_dirty_units: {:?}", dirties);
 
-        self.rev_dep_graph.iter()
+        self.dep_graph.iter()
             // Remove nodes that are not dirty
             .filter(|&(unit, _)| dirties.contains(&unit))
             // Retain only dirty dependencies of the ones that are dirty
@@ -206,59 +276,45 @@ impl Plan {
             .collect()
     }
 
-    /// Returns a topological ordering of a connected DAG of rev deps. The
-    /// output is a stack of units that can be linearly rebuilt, starting from
-    /// the last element.
-    fn topological_sort(&self, dirties: &HashMap<UnitKey, HashSet<UnitKey>>) -> Vec<UnitKey> {
-        let mut visited: HashSet<UnitKey> = HashSet::new();
-        let mut output = vec![];
-
-        for (k, _) in dirties {
-            if !visited.contains(&k) {
-                dfs(k, &self.rev_dep_graph, &mut visited, &mut output);
-            }
-        }
-
-        return output;
-
-        // Process graph depth-first recursively. A node needs to be pushed
-        // after processing every other before to ensure topological ordering.
-        fn dfs(unit: &UnitKey, graph: &HashMap<UnitKey, HashSet<UnitKey>>,
-               visited: &mut HashSet<UnitKey>, output: &mut Vec<UnitKey>) {
-            if visited.contains(&unit) { return; }
-            else {
-                visited.insert(unit.clone());
-                for neighbour in graph.get(&unit).unwrap() {
-                    dfs(neighbour, graph, visited, output);
-                }
-                output.push(unit.clone());
-            }
-        }
-    }
-
     pub fn prepare_work<T: AsRef<Path> + fmt::Debug>(&self, modified: &[T]) -> WorkStatus {
         if self.is_ready() == false { return WorkStatus::NeedsCargo; }
 
         let dirties = self.fetch_dirty_units(modified);
         trace!("fetch_dirty_units: for files {:?}, these units are dirty: {:?}", modified, dirties);
 
-        if dirties.iter().any(|&(_, ref kind)| *kind == TargetKind::CustomBuild) {
-            WorkStatus::NeedsCargo
-        } else {
-            let graph = self.dirty_rev_dep_graph(&dirties);
-            trace!("Constructed dirty rev dep graph: {:?}", graph);
+        self.work_for_dirty_units(&dirties)
+    }
 
-            let queue = self.topological_sort(&graph);
-            trace!("Topologically sorted dirty graph: {:?}", queue);
-            let jobs: Vec<_> = queue.iter()
-                .map(|x| self.compiler_jobs.get(x).unwrap().clone())
-                .collect();
+    /// Returns the work needed to build every unit in the plan, e.g. when
+    /// the plan didn't come from Cargo, so nothing has been built yet.
+    pub fn prepare_full_work(&self) -> WorkStatus {
+        let all_units = self.compiler_jobs.keys().cloned().collect();
+        self.work_for_dirty_units(&all_units)
+    }
 
-            if jobs.is_empty() {
-                WorkStatus::NeedsCargo
-            } else {
-                WorkStatus::Execute(JobQueue(jobs))
-            }
+    fn work_for_dirty_units(&self, dirties: &HashSet<UnitKey>) -> WorkStatus {
+        let graph = self.dirty_dep_graph(dirties);
+        trace!("Constructed dirty dep graph: {:?}", graph);
+
+        // Build scripts are only rerun by Cargo, which runs them in the
+        // complete environment (e.g. with the `DEP_*` variables set by the
+        // build scripts of dependencies) and keeps track of their output.
+        if graph.keys().any(|unit| unit.1 == TargetKind::CustomBuild) {
+            return WorkStatus::NeedsCargo;
+        }
+        // Units which were never compiled by Cargo have no cached invocation.
+        if graph.keys().any(|unit| !self.compiler_jobs.contains_key(unit)) {
+            return WorkStatus::NeedsCargo;
+        }
+
+        let jobs: HashMap<_, _> = graph.keys()
+            .map(|x| (x.clone(), self.compiler_jobs.get(x).unwrap().clone()))
+            .collect();
+
+        if jobs.is_empty() {
+            WorkStatus::NeedsCargo
+        } else {
+            WorkStatus::Execute(JobQueue { jobs, deps: graph })
         }
     }
 }
@@ -268,74 +324,279 @@ pub enum WorkStatus {
     Execute(JobQueue)
 }
 
-pub struct JobQueue(Vec<ProcessBuilder>);
+/// Compiler invocations to be performed for the dirty units, along with the
+/// dependencies between them.
+pub struct JobQueue {
+    /// Cached compiler invocation for every dirty unit.
+    jobs: HashMap<UnitKey, ProcessBuilder>,
+    /// Dirty units that every dirty unit depends on and which have to be
+    /// built before it. Units are removed from here once they're started.
+    deps: HashMap<UnitKey, HashSet<UnitKey>>,
+}
 
 impl JobQueue {
-    pub fn dequeue(&mut self) -> Option<ProcessBuilder> {
-        self.0.pop()
+    /// Returns dirty units which can be built right now, that is, which don't
+    /// wait on any of their dependencies to be built.
+    fn ready_units(&self) -> Vec<UnitKey> {
+        self.deps.iter()
+            .filter(|&(_, deps)| deps.is_empty())
+            .map(|(unit, _)| unit.clone())
+            .collect()
+    }
+
+    /// Marks the unit as built, unblocking units which depend on it.
+    fn mark_built(&mut self, unit: &UnitKey) {
+        for deps in self.deps.values_mut() {
+            deps.remove(unit);
+        }
     }
 
     /// Performs a rustc build using cached compiler invocations.
-    pub(super) fn execute(mut self, internals: &Internals) -> BuildResult {
+    ///
+    /// A unit is only started after all of its dirty dependencies are built.
+    ///
+    /// Compilations run in-process hold the environment lock for their whole
+    /// duration, so these are run one at a time. Only with
+    /// `rustc_out_of_process` are independent units built at the same time,
+    /// using at most `jobs` (from the config) threads.
+    pub(super) fn execute(mut self, internals: &Internals, stats: &BuildRecorder) -> BuildResult {
         // TODO: In case of an empty job queue we shouldn't be here, since the
         // returned results will replace currently held diagnostics/analyses.
         // Either allow to return a BuildResult::Squashed here or just delegate
         // to Cargo (which we do currently) in `prepare_work`
-        assert!(self.0.is_empty() == false);
+        assert!(self.jobs.is_empty() == false);
 
         let build_dir = internals.compilation_cx.lock().unwrap().build_dir.clone().unwrap();
+        let max_jobs = {
+            let config = internals.config.lock().unwrap();
+            match config.jobs {
+                _ if !config.rustc_out_of_process => 1,
+                Some(jobs) => cmp::max(jobs as usize, 1),
+                None => rayon::current_num_threads(),
+            }
+        };
+
+        let compile = {
+            let vfs = internals.vfs.clone();
+            let config = internals.config.clone();
+            let env_lock = internals.env_lock.clone();
+            let abort = internals.abort_build.clone();
+            let build_dir = build_dir.clone();
+            let stats = stats.clone();
+            move |job: &ProcessBuilder| {
+                let mut args: Vec<_> = job.get_args().iter().cloned()
+                    .map(|x| x.into_string().unwrap()).collect();
+                args.insert(0, job.get_program().clone().into_string().unwrap());
+
+                stats.time_unit(&args, || {
+                    super::rustc::rustc(&vfs, &args, job.get_envs(), &build_dir,
+                                        config.clone(), env_lock.as_facade(), abort.clone())
+                })
+            }
+        };
 
+        let mut errored = false;
+        let mut squashed = false;
         let mut compiler_messages = vec![];
         let mut analyses = vec![];
-        // Go through cached compiler invocations sequentially, collecting each
-        // invocation's compiler messages for diagnostics and analysis data
-        while let Some(job) = self.dequeue() {
-            trace!("Executing: {:?}", job);
-            let mut args: Vec<_> = job.get_args().iter().cloned()
-                .map(|x| x.into_string().unwrap()).collect();
-
-            args.insert(0, job.get_program().clone().into_string().unwrap());
-
-            match super::rustc::rustc(&internals.vfs, &args, job.get_envs(),
-                                      &build_dir, internals.config.clone(),
-                                      internals.env_lock.as_facade()) {
+        // Collect each invocation's compiler messages for diagnostics and
+        // analysis data
+        self.run(max_jobs, compile, |unit, result| {
+            match result {
                 BuildResult::Success(mut messages, mut analysis) |
                 BuildResult::Failure(mut messages, mut analysis) => {
+                    // The module tree may have changed since the unit was
+                    // last compiled.
+                    internals.compilation_cx.lock().unwrap()
+                        .build_plan.read_sources_for_key(unit, &build_dir);
                     compiler_messages.append(&mut messages);
                     analyses.append(&mut analysis);
                 },
-                BuildResult::Err => { return BuildResult:: Err },
-                _ => {}
+                // Don't start any more jobs, but wait for the running ones
+                BuildResult::Err => { errored = true; },
+                BuildResult::Squashed => { squashed = true; },
             }
+            !errored && !squashed
+        });
+
+        if errored {
+            return BuildResult::Err;
+        } else if squashed {
+            return BuildResult::Squashed;
         }
         // TODO: Should we combine with ::Failure? What's the difference between those two?
         return BuildResult::Success(compiler_messages, analyses);
     }
+
+    /// Runs the cached compiler invocations with `compile` on a pool of
+    /// `max_jobs` threads, as soon as their dependencies are built. Every
+    /// result is passed to `on_built`, which returns whether any more jobs
+    /// should be started. Returns once all the started jobs are finished.
+    fn run<C, F>(&mut self, max_jobs: usize, compile: C, mut on_built: F)
+        where C: Fn(&ProcessBuilder) -> BuildResult + Send + Sync + 'static,
+              F: FnMut(UnitKey, BuildResult) -> bool {
+        let pool = rayon::ThreadPool::new(
+            rayon::Configuration::new()
+                .num_threads(max_jobs)
+                .thread_name(|num| format!("build-worker-{}", num))
+        ).unwrap();
+        let compile = Arc::new(compile);
+
+        let (sender, receiver) = mpsc::channel();
+        let mut running = 0;
+        let mut start_more = true;
+        loop {
+            if start_more {
+                for unit in self.ready_units().into_iter().take(max_jobs - running) {
+                    self.deps.remove(&unit);
+                    let job = self.jobs.remove(&unit).unwrap();
+                    trace!("Executing: {:?}", job);
+
+                    let compile = compile.clone();
+                    let sender = sender.clone();
+                    running += 1;
+                    pool.spawn(move || {
+                        // Every job has to report back, or we'd wait for it
+                        // forever.
+                        let result = panic::catch_unwind(AssertUnwindSafe(|| compile(&job)))
+                            .unwrap_or(BuildResult::Err);
+                        let _ = sender.send((unit, result));
+                    });
+                }
+            }
+
+            if running == 0 {
+                break;
+            }
+
+            let (unit, result) = receiver.recv().unwrap();
+            running -= 1;
+            self.mark_built(&unit);
+            start_more = on_built(unit, result);
+        }
+    }
 }
 
 fn key_from_unit(unit: &Unit) -> UnitKey {
-    (unit.pkg.package_id().clone(), unit.target.kind().clone())
+    unit_key(unit.pkg.package_id(), unit.target, unit.profile.test)
 }
 
-macro_rules! print_dep_graph {
-    ($name: expr, $graph: expr, $f: expr) => {
-        $f.write_str(&format!("{}:\n", $name))?;
-        for (key, deps) in &$graph {
-            $f.write_str(&format!("{:?}\n", key))?;
-            for dep in deps {
-                $f.write_str(&format!("- {:?}\n", dep))?;
+fn unit_key(id: &PackageId, target: &Target, test: bool) -> UnitKey {
+    (id.clone(), target.kind().clone(), target.name().to_owned(), test)
+}
+
+fn kind_name(kind: &TargetKind) -> &'static str {
+    match *kind {
+        TargetKind::Lib(_) => "lib",
+        TargetKind::Bin => "bin",
+        TargetKind::Test => "test",
+        TargetKind::Bench => "bench",
+        TargetKind::ExampleLib(_) | TargetKind::ExampleBin => "example",
+        TargetKind::CustomBuild => "custom-build",
+    }
+}
+
+/// A serializable description of the build plan, which shows what's rebuilt
+/// and how (e.g. with which flags), without having to enable trace logging.
+#[derive(Debug, Serialize)]
+pub struct SerializedPlan {
+    /// Every unit known to the plan.
+    pub units: Vec<SerializedUnit>,
+}
+
+/// A unit of the build plan, along with its cached compiler invocation.
+#[derive(Debug, Serialize)]
+pub struct SerializedUnit {
+    /// Id of the package the unit belongs to.
+    pub package_id: String,
+    /// Kind of the crate target (e.g. `lib` or `custom-build`).
+    pub kind: &'static str,
+    /// Name of the crate target.
+    pub target: String,
+    /// Whether the crate target is built as a test harness.
+    pub test: bool,
+    /// Root source file of the crate target, if known.
+    pub src_path: Option<PathBuf>,
+    /// Indices of the units this one depends on.
+    pub deps: Vec<usize>,
+    /// Source files read from the dep-info, if known.
+    pub sources: Option<Vec<PathBuf>>,
+    /// Compiler invocation used to rebuild the unit, if cached.
+    pub compiler_job: Option<SerializedJob>,
+}
+
+/// A cached compiler invocation.
+#[derive(Debug, Serialize)]
+pub struct SerializedJob {
+    /// The compiler executable.
+    pub program: String,
+    /// Arguments passed to the compiler.
+    pub args: Vec<String>,
+    /// Environment variables set (or removed, if `None`) for the compiler.
+    pub env: BTreeMap<String, Option<String>>,
+    /// Directory the compiler is run in, if not the build directory.
+    pub cwd: Option<PathBuf>,
+}
+
+impl Plan {
+    /// Describes every unit known to the plan, sorted by package and target.
+    pub fn serialize(&self) -> SerializedPlan {
+        let keys: HashSet<&UnitKey> = self.units.keys()
+            .chain(self.dep_graph.keys())
+            .chain(self.compiler_jobs.keys())
+            .collect();
+        let mut keys: Vec<&UnitKey> = keys.into_iter().collect();
+        keys.sort_by_key(|&&(ref id, ref kind, ref name, test)| (id.to_string(), kind_name(kind), name.clone(), test));
+        let indices: HashMap<&UnitKey, usize> = keys.iter().enumerate().map(|(i, key)| (*key, i)).collect();
+
+        let units = keys.iter().map(|key| {
+            let mut deps: Vec<usize> = self.dep_graph.get(*key).into_iter()
+                .flat_map(|deps| deps.iter().filter_map(|dep| indices.get(dep).cloned()))
+                .collect();
+            deps.sort();
+            let mut sources: Option<Vec<PathBuf>> = self.unit_sources.get(*key)
+                .map(|sources| sources.iter().cloned().collect());
+            if let Some(ref mut sources) = sources {
+                sources.sort();
             }
-        }
+
+            SerializedUnit {
+                package_id: key.0.to_string(),
+                kind: kind_name(&key.1),
+                target: key.2.clone(),
+                test: key.3,
+                src_path: self.units.get(*key).map(|unit| unit.target.src_path().to_owned()),
+                deps,
+                sources,
+                compiler_job: self.compiler_jobs.get(*key).map(serialize_job),
+            }
+        }).collect();
+
+        SerializedPlan { units }
     }
 }
 
+fn serialize_job(job: &ProcessBuilder) -> SerializedJob {
+    SerializedJob {
+        program: os_to_string(job.get_program()),
+        args: job.get_args().iter().map(|arg| os_to_string(arg)).collect(),
+        env: job.get_envs().iter()
+            .map(|(key, value)| (key.clone(), value.as_ref().map(|v| os_to_string(v))))
+            .collect(),
+        cwd: job.get_cwd().map(|cwd| cwd.to_owned()),
+    }
+}
+
+fn os_to_string(s: &OsStr) -> String {
+    s.to_string_lossy().into_owned()
+}
+
 impl fmt::Debug for Plan {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        f.write_str(&format!("Units: {:?}\n", self.units))?;
-        print_dep_graph!("Dependency graph", self.dep_graph, f);
-        print_dep_graph!("Reverse dependency graph", self.rev_dep_graph, f);
-        f.write_str(&format!("Compiler jobs: {:?}\n", self.compiler_jobs))?;
-        Ok(())
+        match serde_json::to_string_pretty(&self.serialize()) {
+            Ok(json) => f.write_str(&json),
+            Err(_) => Err(fmt::Error),
+        }
     }
 }
 
@@ -358,3 +619,254 @@ impl<'a> From<&'a Unit<'a>> for OwnedUnit {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use cargo::core::{LibKind, SourceId};
+    use cargo::util::process;
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::sync::Mutex;
+    use std::thread;
+    use std::time::Duration;
+
+    fn key(name: &str) -> UnitKey {
+        let source = SourceId::for_path(&Path::new("/project").join(name)).unwrap();
+        let id = PackageId::new(name, "0.1.0", &source).unwrap();
+        (id, TargetKind::Lib(vec![LibKind::Lib]), name.to_owned(), false)
+    }
+
+    // A plan with a compiler job for every unit, where each is given along
+    // with the units it depends on.
+    fn plan(units: &[(&str, &[&str])]) -> Plan {
+        let mut plan = Plan::new();
+        for &(unit, deps) in units {
+            let deps: HashSet<_> = deps.iter().map(|dep| key(dep)).collect();
+            plan.rev_dep_graph.entry(key(unit)).or_insert_with(HashSet::new);
+            for dep in &deps {
+                plan.rev_dep_graph.entry(dep.clone()).or_insert_with(HashSet::new).insert(key(unit));
+            }
+            plan.dep_graph.insert(key(unit), deps);
+            plan.compiler_jobs.insert(key(unit), process("rustc"));
+        }
+        plan
+    }
+
+    fn queue(plan: &Plan, dirty: &[&str]) -> JobQueue {
+        match plan.work_for_dirty_units(&dirty.iter().map(|unit| key(unit)).collect()) {
+            WorkStatus::Execute(queue) => queue,
+            WorkStatus::NeedsCargo => panic!("no work for {:?}", dirty),
+        }
+    }
+
+    fn sorted(mut units: Vec<UnitKey>) -> Vec<String> {
+        units.sort_by_key(|unit| unit.2.clone());
+        units.into_iter().map(|unit| unit.2).collect()
+    }
+
+    // Starts the ready units, as `JobQueue::run` does.
+    fn start(queue: &mut JobQueue) -> Vec<String> {
+        let ready = queue.ready_units();
+        for unit in &ready {
+            queue.deps.remove(unit);
+        }
+        sorted(ready)
+    }
+
+    #[test]
+    fn test_dirty_dep_graph() {
+        // `app` and `tool` depend on `core` through `util`, `other` is unrelated.
+        let plan = plan(&[("core", &[]), ("util", &["core"]), ("app", &["util", "core"]),
+                          ("tool", &["util"]), ("other", &[])]);
+
+        let graph = plan.dirty_dep_graph(&vec![key("util")].into_iter().collect());
+        assert_eq!(sorted(graph.keys().cloned().collect()), vec!["app", "tool", "util"]);
+        // Clean dependencies aren't waited on.
+        assert_eq!(sorted(graph[&key("app")].iter().cloned().collect()), vec!["util"]);
+        assert!(graph[&key("util")].is_empty());
+
+        let graph = plan.dirty_dep_graph(&vec![key("other")].into_iter().collect());
+        assert_eq!(sorted(graph.keys().cloned().collect()), vec!["other"]);
+    }
+
+    #[test]
+    fn test_job_queue_order() {
+        let plan = plan(&[("core", &[]), ("util", &["core"]), ("app", &["util", "core"]),
+                          ("tool", &["util"]), ("other", &[])]);
+        let mut queue = queue(&plan, &["core", "other"]);
+        assert_eq!(queue.jobs.len(), 5);
+
+        assert_eq!(start(&mut queue), vec!["core", "other"]);
+        // Nothing else can start until `core` is built.
+        assert!(start(&mut queue).is_empty());
+        queue.mark_built(&key("other"));
+        assert!(start(&mut queue).is_empty());
+        queue.mark_built(&key("core"));
+        assert_eq!(start(&mut queue), vec!["util"]);
+        queue.mark_built(&key("util"));
+        // Both dependents of `util` can be built at the same time.
+        assert_eq!(start(&mut queue), vec!["app", "tool"]);
+        queue.mark_built(&key("app"));
+        queue.mark_built(&key("tool"));
+        assert!(queue.deps.is_empty());
+    }
+
+    #[test]
+    fn test_job_queue_max_jobs() {
+        let plan = plan(&[("core", &[]), ("a", &["core"]), ("b", &["core"]), ("c", &["core"]),
+                          ("d", &["core"]), ("other", &[])]);
+        let mut queue = queue(&plan, &["core", "other"]);
+
+        // The number of running jobs, and the most jobs that ever ran at once.
+        let running = Arc::new(Mutex::new((0, 0)));
+        let compile = {
+            let running = running.clone();
+            move |_: &ProcessBuilder| {
+                {
+                    let mut running = running.lock().unwrap();
+                    running.0 += 1;
+                    running.1 = cmp::max(running.0, running.1);
+                }
+                thread::sleep(Duration::from_millis(50));
+                running.lock().unwrap().0 -= 1;
+                BuildResult::Success(vec![], vec![])
+            }
+        };
+        let mut built = vec![];
+        queue.run(2, compile, |unit, _| {
+            built.push(unit.2);
+            true
+        });
+
+        assert_eq!(running.lock().unwrap().1, 2);
+        assert_eq!(built.len(), 6);
+        // Dependents are only started once `core` is built.
+        let core = built.iter().position(|unit| unit == "core").unwrap();
+        for unit in &["a", "b", "c", "d"] {
+            assert!(built.iter().position(|built| built == unit).unwrap() > core);
+        }
+    }
+
+    #[test]
+    fn test_fetch_dirty_units() {
+        let dir = ::std::env::current_dir().unwrap().join("target").join("tests").join("plan_dirty_units");
+        for file in &["lib/src/lib.rs", "lib/examples/example.rs", "bin/src/main.rs", "shared/util.rs",
+                      "dep/src/lib.rs", "dep/src/module.rs", "other.rs"] {
+            let path = dir.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            File::create(path).unwrap();
+        }
+        fs::create_dir_all(dir.join("out")).unwrap();
+
+        // Both crates include `shared/util.rs` as a `#[path]` module, which
+        // is outside of their directories.
+        let mut plan = plan(&[("lib", &[]), ("bin", &["lib"])]);
+        for &(unit, root) in &[("lib", "lib/src/lib.rs"), ("bin", "bin/src/main.rs")] {
+            let mut job = process("rustc");
+            job.args(&["--crate-name", unit, root, "--emit=dep-info,metadata", "--out-dir", "out"]);
+            plan.compiler_jobs.insert(key(unit), job);
+            File::create(dir.join("out").join(format!("{}.d", unit))).unwrap()
+                .write_all(format!("out/{}.rmeta: {} shared/util.rs\n", unit, root).as_bytes()).unwrap();
+            plan.read_sources_for_key(key(unit), &dir);
+        }
+        // A dependency whose source files aren't known, as it was never
+        // compiled by us.
+        plan.units.insert(key("dep"), OwnedUnit {
+            id: key("dep").0,
+            target: Target::lib_target("dep", vec![LibKind::Lib], dir.join("dep/src/lib.rs")),
+            profile: Profile::default(),
+            kind: Kind::Host,
+        });
+
+        let dirty = |file: &str| sorted(plan.fetch_dirty_units(&[dir.join(file)]).into_iter().collect());
+        assert_eq!(dirty("lib/src/lib.rs"), vec!["lib"]);
+        assert_eq!(dirty("bin/src/main.rs"), vec!["bin"]);
+        assert_eq!(dirty("shared/util.rs"), vec!["bin", "lib"]);
+        // Paths are compared once normalized.
+        assert_eq!(dirty("lib/../shared/util.rs"), vec!["bin", "lib"]);
+        assert_eq!(dirty("dep/src/module.rs"), vec!["dep"]);
+        // Files which belong to no unit don't make anything dirty.
+        assert!(dirty("other.rs").is_empty());
+        assert!(dirty("lib/examples/example.rs").is_empty());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_serialize() {
+        let mut plan = plan(&[("core", &[]), ("app", &["util", "core"]), ("util", &["core"])]);
+        let mut job = process("rustc");
+        job.args(&["--crate-name", "app", "src/main.rs"]).env("APP_VERSION", "1.0").cwd("/project/app");
+        plan.compiler_jobs.insert(key("app"), job);
+        plan.unit_sources.insert(key("app"), vec![PathBuf::from("/project/app/src/main.rs"),
+                                                  PathBuf::from("/project/app/src/lib.rs")]
+                                                 .into_iter().collect());
+
+        let serialized = plan.serialize();
+        // Units are sorted by package, so dependencies refer to their indices.
+        let names: Vec<_> = serialized.units.iter().map(|unit| &unit.target[..]).collect();
+        assert_eq!(names, vec!["app", "core", "util"]);
+        assert_eq!(serialized.units[0].kind, "lib");
+        assert!(!serialized.units[0].test);
+        assert_eq!(serialized.units[0].deps, vec![1, 2]);
+        assert_eq!(serialized.units[2].deps, vec![1]);
+        assert!(serialized.units[1].deps.is_empty());
+        assert_eq!(serialized.units[0].sources, Some(vec![PathBuf::from("/project/app/src/lib.rs"),
+                                                          PathBuf::from("/project/app/src/main.rs")]));
+        assert_eq!(serialized.units[1].sources, None);
+
+        let job = serialized.units[0].compiler_job.as_ref().unwrap();
+        assert_eq!(job.program, "rustc");
+        assert_eq!(job.args, vec!["--crate-name", "app", "src/main.rs"]);
+        assert_eq!(job.env.get("APP_VERSION"), Some(&Some("1.0".to_owned())));
+        assert_eq!(job.cwd, Some(PathBuf::from("/project/app")));
+        assert_eq!(serialized.units[1].compiler_job.as_ref().unwrap().cwd, None);
+
+        let json = serde_json::to_value(&serialized).unwrap();
+        assert_eq!(json["units"][0]["target"].as_str(), Some("app"));
+        assert_eq!(json["units"][0]["compiler_job"]["env"]["APP_VERSION"].as_str(), Some("1.0"));
+    }
+
+    #[test]
+    fn test_work_needs_cargo() {
+        let mut plan = plan(&[("core", &[]), ("util", &["core"])]);
+        // Nothing to do.
+        match plan.work_for_dirty_units(&HashSet::new()) {
+            WorkStatus::NeedsCargo => {}
+            WorkStatus::Execute(_) => panic!("unexpected work"),
+        }
+        // A dirty unit that was never compiled by Cargo.
+        plan.compiler_jobs.remove(&key("util"));
+        match plan.work_for_dirty_units(&vec![key("core")].into_iter().collect()) {
+            WorkStatus::NeedsCargo => {}
+            WorkStatus::Execute(_) => panic!("unexpected work"),
+        }
+    }
+
+    #[test]
+    fn test_dirty_build_script_needs_cargo() {
+        let mut plan = plan(&[("core", &[]), ("util", &["core"])]);
+        // `util` depends on its build script, and even compiling the build
+        // script alone could be done from the plan.
+        let build_script = (key("util").0, TargetKind::CustomBuild, "build-script-build".to_owned(), false);
+        plan.units.insert(build_script.clone(), OwnedUnit {
+            id: key("util").0,
+            target: Target::custom_build_target("build-script-build", PathBuf::from("/project/util/build.rs")),
+            profile: Profile::default(),
+            kind: Kind::Host,
+        });
+        plan.dep_graph.insert(build_script.clone(), HashSet::new());
+        plan.dep_graph.get_mut(&key("util")).unwrap().insert(build_script.clone());
+        plan.rev_dep_graph.insert(build_script.clone(), vec![key("util")].into_iter().collect());
+        plan.compiler_jobs.insert(build_script.clone(), process("rustc"));
+
+        match plan.prepare_work(&[Path::new("/project/util/build.rs")]) {
+            WorkStatus::NeedsCargo => {}
+            WorkStatus::Execute(_) => panic!("build script rerun without Cargo"),
+        }
+        // Dependents of a clean build script are still built from the plan.
+        let queue = queue(&plan, &["core"]);
+        assert_eq!(sorted(queue.jobs.keys().cloned().collect()), vec!["core", "util"]);
+    }
+}
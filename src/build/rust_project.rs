@@ -0,0 +1,382 @@
+// Copyright 2017 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Support for projects which aren't built with Cargo (e.g. with Bazel or
+//! Buck), but describe their crates in a `rust-project.json` file in the
+//! project root instead. The description is turned directly into a build
+//! plan, whose compiler invocations are then run the same way as the ones
+//! cached from Cargo.
+//!
+//! An example description, where the `bar` binary depends on the `foo` library:
+//!
+//! ```json
+//! {
+//!     "crates": [
+//!         { "name": "foo", "root_module": "foo/lib.rs", "cfg": ["feature=\"std\""] },
+//!         {
+//!             "name": "bar",
+//!             "root_module": "bar/main.rs",
+//!             "crate_type": "bin",
+//!             "deps": [{ "crate": 0, "name": "foo" }],
+//!             "env": { "BAR_VERSION": "1.0" }
+//!         }
+//!     ]
+//! }
+//! ```
+
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use cargo::core::{LibKind, PackageId, SourceId, TargetKind};
+use cargo::util::{process, ProcessBuilder};
+use serde_json;
+
+use config::Config;
+use super::{BuildRecorder, BuildResult, Internals};
+use super::plan::{Plan, UnitKey, WorkStatus};
+
+const PROJECT_FILE_NAME: &'static str = "rust-project.json";
+
+/// Returns the `rust-project.json` describing the project, unless the project
+/// is a Cargo one.
+pub fn find_rust_project(project_dir: &Path) -> Option<PathBuf> {
+    if project_dir.join("Cargo.toml").exists() {
+        return None;
+    }
+    let path = project_dir.join(PROJECT_FILE_NAME);
+    if path.exists() { Some(path) } else { None }
+}
+
+/// Contents of a `rust-project.json` file.
+#[derive(Debug, Deserialize)]
+pub struct ProjectDescription {
+    crates: Vec<CrateDescription>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CrateDescription {
+    name: String,
+    // Relative to the directory of the project file.
+    root_module: PathBuf,
+    // Only the 2015 edition is supported by the compiler.
+    #[serde(default = "default_edition")]
+    edition: String,
+    #[serde(default = "default_crate_type")]
+    crate_type: String,
+    // Passed as `--cfg` flags, e.g. `test` or `feature="std"`.
+    #[serde(default)]
+    cfg: Vec<String>,
+    #[serde(default)]
+    deps: Vec<DepDescription>,
+    // Environment variables set when compiling the crate (e.g. for `env!`).
+    #[serde(default)]
+    env: HashMap<String, String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DepDescription {
+    // Index of the crate in the `crates` list.
+    #[serde(rename = "crate")]
+    krate: usize,
+    // Name the dependency is imported as.
+    name: String,
+}
+
+fn default_edition() -> String {
+    "2015".to_owned()
+}
+
+fn default_crate_type() -> String {
+    "lib".to_owned()
+}
+
+impl ProjectDescription {
+    /// Reads and validates the project description.
+    pub fn load(path: &Path) -> Result<ProjectDescription, String> {
+        let file = File::open(path).map_err(|e| format!("couldn't open {:?}: {}", path, e))?;
+        let project: ProjectDescription = serde_json::from_reader(io::BufReader::new(file))
+            .map_err(|e| format!("malformed project description {:?}: {}", path, e))?;
+        project.validate()?;
+        Ok(project)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        for (i, krate) in self.crates.iter().enumerate() {
+            if krate.edition != "2015" {
+                return Err(format!("crate `{}` uses the {} edition, but only the 2015 edition \
+                                    is supported", krate.name, krate.edition));
+            }
+            for dep in &krate.deps {
+                if dep.krate >= self.crates.len() || dep.krate == i {
+                    return Err(format!("crate `{}` has an invalid dependency on crate {}",
+                                       krate.name, dep.krate));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Fills the build plan with the dependency graph of the described crates
+    /// and a compiler invocation for each of them. Relative paths are relative
+    /// to `root_dir`.
+    pub fn fill_plan(&self, root_dir: &Path, config: &Config, plan: &mut Plan) -> Result<(), String> {
+        let target_dir = config.target_dir.clone()
+            .unwrap_or_else(|| root_dir.join("target").join("rls"));
+        // Mirror Cargo's layout, where the save-analysis data is looked for.
+        let out_dir = target_dir.join("debug").join("deps");
+        fs::create_dir_all(&out_dir).map_err(|e| format!("couldn't create {:?}: {}", out_dir, e))?;
+
+        let keys = self.crates.iter()
+            .map(|krate| unit_key(krate, root_dir))
+            .collect::<Result<Vec<_>, _>>()?;
+        let common_args = common_args(config);
+
+        for (i, krate) in self.crates.iter().enumerate() {
+            let job = self.compiler_job(i, root_dir, &out_dir, &common_args);
+            plan.compiler_jobs.insert(keys[i].clone(), job);
+
+            let deps: HashSet<UnitKey> = krate.deps.iter().map(|dep| keys[dep.krate].clone()).collect();
+            plan.rev_dep_graph.entry(keys[i].clone()).or_insert_with(HashSet::new);
+            for dep in &deps {
+                plan.rev_dep_graph.entry(dep.clone()).or_insert_with(HashSet::new).insert(keys[i].clone());
+            }
+            plan.dep_graph.insert(keys[i].clone(), deps);
+        }
+        Ok(())
+    }
+
+    fn compiler_job(&self, index: usize, root_dir: &Path, out_dir: &Path, common_args: &[String]) -> ProcessBuilder {
+        let krate = &self.crates[index];
+        let mut job = process("rustc");
+        job.cwd(root_dir);
+        job.arg("--crate-name").arg(crate_name(krate))
+           .arg(root_dir.join(&krate.root_module))
+           .arg("--crate-type").arg(&krate.crate_type)
+           .arg("--emit=dep-info,metadata")
+           .arg("-C").arg(format!("metadata=rls-project-{}", index))
+           .arg("-C").arg(format!("extra-filename={}", extra_filename(index)))
+           .arg("--out-dir").arg(out_dir)
+           .arg("-L").arg(format!("dependency={}", out_dir.display()));
+        for cfg in &krate.cfg {
+            job.arg("--cfg").arg(cfg);
+        }
+        for dep in &krate.deps {
+            let rmeta = format!("lib{}{}.rmeta", crate_name(&self.crates[dep.krate]), extra_filename(dep.krate));
+            job.arg("--extern").arg(format!("{}={}", dep.name, out_dir.join(rmeta).display()));
+        }
+        job.args(common_args);
+        for (key, value) in &krate.env {
+            job.env(key, value);
+        }
+        job
+    }
+}
+
+/// The project description the build plan was last filled from, which is
+/// kept as the build context instead of the one Cargo would provide.
+#[derive(Debug, PartialEq)]
+pub struct LoadedProject {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    // The arguments depending on the config, which the plan has to be filled
+    // again for when they change.
+    common_args: Vec<String>,
+}
+
+/// Builds the crates of the project described by `project_file`. The build
+/// plan is only filled (and every crate built) when the description or the
+/// relevant config changed since the last build, otherwise only the crates
+/// affected by the modified files are rebuilt.
+pub(super) fn build(internals: &Internals, project_file: &Path, stats: &BuildRecorder) -> BuildResult {
+    let root_dir = project_file.parent().unwrap();
+    let mut config = internals.config.lock().unwrap().clone();
+    let modified_files: Vec<_> = internals.dirty_files.lock().unwrap().keys().cloned().collect();
+
+    let work = {
+        let mut cx = internals.compilation_cx.lock().unwrap();
+        if config.sysroot.is_none() {
+            config.sysroot = cx.sysroot.clone();
+        }
+        let loaded = LoadedProject {
+            path: project_file.to_owned(),
+            modified: fs::metadata(project_file).and_then(|m| m.modified()).ok(),
+            common_args: common_args(&config),
+        };
+
+        if cx.rust_project.as_ref() == Some(&loaded) && cx.build_plan.is_ready() {
+            match cx.build_plan.prepare_work(&modified_files) {
+                // Nothing's dirty, so this is a rebuild of the whole project.
+                WorkStatus::NeedsCargo => cx.build_plan.prepare_full_work(),
+                work => work,
+            }
+        } else {
+            let project = match ProjectDescription::load(project_file) {
+                Ok(project) => project,
+                Err(e) => {
+                    info!("Couldn't load the project description: {}", e);
+                    return BuildResult::Err;
+                }
+            };
+            cx.build_plan.clear();
+            cx.rust_project = None;
+            if let Err(e) = project.fill_plan(root_dir, &config, &mut cx.build_plan) {
+                info!("Couldn't create the build plan from {:?}: {}", project_file, e);
+                return BuildResult::Err;
+            }
+            trace!("Build plan from {:?}: {:?}", project_file, cx.build_plan);
+            cx.rust_project = Some(loaded);
+            cx.build_plan.prepare_full_work()
+        }
+    };
+    match work {
+        WorkStatus::Execute(job_queue) => job_queue.execute(internals, stats),
+        WorkStatus::NeedsCargo => {
+            info!("No crates described in {:?}", project_file);
+            BuildResult::Err
+        }
+    }
+}
+
+// Every crate needs a distinct package id, so we use the directory of its
+// root module as the package source.
+fn unit_key(krate: &CrateDescription, root_dir: &Path) -> Result<UnitKey, String> {
+    let root_module = root_dir.join(&krate.root_module);
+    let crate_dir = root_module.parent().unwrap_or(root_dir);
+    let id = SourceId::for_path(crate_dir)
+        .and_then(|source| PackageId::new(&krate.name, "0.0.0", &source))
+        .map_err(|e| format!("invalid crate `{}`: {}", krate.name, e))?;
+    let kind = match &krate.crate_type[..] {
+        "bin" => TargetKind::Bin,
+        "proc-macro" => TargetKind::Lib(vec![LibKind::ProcMacro]),
+        _ => TargetKind::Lib(vec![LibKind::Lib]),
+    };
+    Ok((id, kind, krate.name.clone(), false))
+}
+
+fn crate_name(krate: &CrateDescription) -> String {
+    krate.name.replace('-', "_")
+}
+
+fn extra_filename(index: usize) -> String {
+    format!("-rls-project-{}", index)
+}
+
+// Arguments passed to every compiler invocation, like Cargo does via RUSTFLAGS.
+fn common_args(config: &Config) -> Vec<String> {
+    let mut args = vec!["--error-format=json".to_owned()];
+    if let Some(ref sysroot) = config.sysroot {
+        args.push("--sysroot".to_owned());
+        args.push(sysroot.clone());
+    }
+    if let Some(ref target) = config.target {
+        args.push("--target".to_owned());
+        args.push(target.clone());
+    }
+    if let Some(ref rustflags) = config.rustflags {
+        args.extend(rustflags.split_whitespace().map(|flag| flag.to_owned()));
+    }
+    args
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::ffi::OsString;
+
+    fn project(json: &str) -> ProjectDescription {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn test_compiler_jobs() {
+        let project = project(r#"{ "crates": [
+            { "name": "foo-sys", "root_module": "foo/lib.rs", "cfg": ["feature=\"std\""] },
+            {
+                "name": "bar",
+                "root_module": "bar/main.rs",
+                "crate_type": "bin",
+                "deps": [{ "crate": 0, "name": "foo" }],
+                "env": { "BAR_VERSION": "1.0" }
+            }
+        ] }"#);
+        let root_dir = Path::new("/project");
+        let out_dir = Path::new("/project/target/rls/debug/deps");
+
+        let job = project.compiler_job(1, root_dir, out_dir, &[]);
+        let args: Vec<_> = job.get_args().iter().map(|a| a.to_str().unwrap()).collect();
+        assert_eq!(args, vec!["--crate-name", "bar", "/project/bar/main.rs", "--crate-type", "bin",
+                              "--emit=dep-info,metadata", "-C", "metadata=rls-project-1",
+                              "-C", "extra-filename=-rls-project-1",
+                              "--out-dir", "/project/target/rls/debug/deps",
+                              "-L", "dependency=/project/target/rls/debug/deps", "--extern",
+                              "foo=/project/target/rls/debug/deps/libfoo_sys-rls-project-0.rmeta"]);
+        assert_eq!(job.get_envs().get("BAR_VERSION"), Some(&Some(OsString::from("1.0"))));
+
+        let job = project.compiler_job(0, root_dir, out_dir, &[]);
+        let args: Vec<_> = job.get_args().iter().map(|a| a.to_str().unwrap()).collect();
+        assert_eq!(&args[..2], &["--crate-name", "foo_sys"]);
+        assert_eq!(&args[args.len() - 2..], &["--cfg", "feature=\"std\""]);
+    }
+
+    #[test]
+    fn test_validate() {
+        let valid = project(r#"{ "crates": [
+            { "name": "foo", "root_module": "foo/lib.rs", "edition": "2015" },
+            { "name": "bar", "root_module": "bar/main.rs", "deps": [{ "crate": 0, "name": "foo" }] }
+        ] }"#);
+        assert!(valid.validate().is_ok());
+
+        let edition = project(r#"{ "crates": [
+            { "name": "foo", "root_module": "foo/lib.rs", "edition": "2018" }
+        ] }"#);
+        assert_eq!(edition.validate().unwrap_err(),
+                   "crate `foo` uses the 2018 edition, but only the 2015 edition is supported");
+
+        let self_dep = project(r#"{ "crates": [
+            { "name": "foo", "root_module": "foo/lib.rs", "deps": [{ "crate": 0, "name": "foo" }] }
+        ] }"#);
+        assert!(self_dep.validate().is_err());
+
+        let missing_dep = project(r#"{ "crates": [
+            { "name": "foo", "root_module": "foo/lib.rs", "deps": [{ "crate": 1, "name": "bar" }] }
+        ] }"#);
+        assert!(missing_dep.validate().is_err());
+    }
+
+    #[test]
+    fn test_dep_graph() {
+        let project = project(r#"{ "crates": [
+            { "name": "foo", "root_module": "foo/lib.rs" },
+            { "name": "bar", "root_module": "bar/main.rs", "crate_type": "bin",
+              "deps": [{ "crate": 0, "name": "foo" }] }
+        ] }"#);
+        let mut config = Config::default();
+        let target_dir = ::std::env::current_dir().unwrap()
+            .join("target").join("tests").join("rust_project");
+        config.target_dir = Some(target_dir.clone());
+        config.sysroot = Some("/sysroot".to_owned());
+
+        let mut plan = Plan::new();
+        project.fill_plan(Path::new("/project"), &config, &mut plan).unwrap();
+        let foo = unit_key(&project.crates[0], Path::new("/project")).unwrap();
+        let bar = unit_key(&project.crates[1], Path::new("/project")).unwrap();
+
+        assert_eq!(plan.compiler_jobs.len(), 2);
+        assert!(plan.dep_graph[&bar].contains(&foo));
+        assert!(plan.dep_graph[&foo].is_empty());
+        assert!(plan.rev_dep_graph[&foo].contains(&bar));
+        assert!(plan.rev_dep_graph[&bar].is_empty());
+
+        fs::remove_dir_all(&target_dir).unwrap();
+    }
+}
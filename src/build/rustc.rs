@@ -30,37 +30,86 @@ use config::Config;
 use build::{BufWriter, BuildResult};
 use build::environment::{Environment, EnvironmentLockFacade};
 use data::Analysis;
+use serde_json;
 use vfs::Vfs;
 
 use std::collections::HashMap;
+use std::env;
 use std::ffi::OsString;
-use std::io;
+use std::io::{self, Read, Write};
 use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
 
 
-// Runs a single instance of rustc. Runs in-process.
-pub fn rustc(vfs: &Vfs, args: &[String], envs: &HashMap<String, Option<OsString>>, build_dir: &Path, rls_config: Arc<Mutex<Config>>, env_lock: EnvironmentLockFacade) -> BuildResult {
+/// Release and commit hash of the compiler the RLS is linked against, if these
+/// are known.
+pub(super) fn linked_version() -> Option<(&'static str, &'static str)> {
+    Some((rustc_driver::release_str()?, rustc_driver::commit_hash_str()?))
+}
+
+// Runs a single instance of rustc. Runs in-process, unless
+// `rustc_out_of_process` is set. The compilation is stopped between compiler
+// phases (or the child process is killed) once `abort` is set, in which case
+// it's squashed. A compilation which finished before that isn't.
+pub fn rustc(vfs: &Vfs, args: &[String], envs: &HashMap<String, Option<OsString>>, build_dir: &Path, rls_config: Arc<Mutex<Config>>, env_lock: EnvironmentLockFacade, abort: Arc<AtomicBool>) -> BuildResult {
     trace!("rustc - args: `{:?}`, envs: {:?}, build dir: {:?}", args, envs, build_dir);
 
     let changed = vfs.get_cached_files();
 
     let mut local_envs = envs.clone();
 
-    if rls_config.lock().unwrap().clear_env_rust_log {
-        local_envs.insert(String::from("RUST_LOG"), None);
+    let out_of_process = {
+        let config = rls_config.lock().unwrap();
+        if config.clear_env_rust_log {
+            local_envs.insert(String::from("RUST_LOG"), None);
+        }
+        config.rustc_out_of_process
+    };
+
+    if out_of_process {
+        return match rustc_out_of_process(args, &local_envs, changed, abort) {
+            Ok(result) => result,
+            Err(e) => {
+                info!("rustc failed: {}", e);
+                BuildResult::Err
+            }
+        };
     }
 
     let (guard, _) = env_lock.lock();
     let _restore_env = Environment::push_with_lock(&local_envs, guard);
 
+    let stopped = Arc::new(AtomicBool::new(false));
+    let (success, stderr_json_msgs, analysis) = compile(args.to_owned(), changed, abort, stopped.clone());
+
+    if stopped.load(Ordering::SeqCst) {
+        trace!("rustc - aborted");
+        return BuildResult::Squashed;
+    }
+
+    let analysis = analysis.map(|analysis| vec![analysis]).unwrap_or(vec![]);
+    if success {
+        BuildResult::Success(stderr_json_msgs, analysis)
+    } else {
+        BuildResult::Failure(stderr_json_msgs, analysis)
+    }
+}
+
+// Runs the compiler in the current process, using the current environment.
+// Returns whether the compilation succeeded, the JSON diagnostics and the
+// save-analysis data. `stopped` is set if the compilation was stopped early
+// because of `abort`.
+fn compile(args: Vec<String>, changed: HashMap<PathBuf, String>, abort: Arc<AtomicBool>, stopped: Arc<AtomicBool>) -> (bool, Vec<String>, Option<Analysis>) {
     let buf = Arc::new(Mutex::new(vec![]));
     let err_buf = buf.clone();
-    let args = args.to_owned();
 
     let analysis = Arc::new(Mutex::new(None));
 
-    let mut controller = RlsRustcCalls::new(analysis.clone());
+    let mut controller = RlsRustcCalls::new(analysis.clone(), abort, stopped);
 
     let exit_code = ::std::panic::catch_unwind(|| {
         run(move || {
@@ -79,11 +128,124 @@ pub fn rustc(vfs: &Vfs, args: &[String], envs: &HashMap<String, Option<OsString>
     let stderr_json_msgs: Vec<_> = err_buf.lines().map(String::from).collect();
 
     let analysis = analysis.lock().unwrap().clone();
-    let analysis = analysis.map(|analysis| vec![analysis]).unwrap_or(vec![]);
-    match exit_code {
-        Ok(0) => BuildResult::Success(stderr_json_msgs, analysis),
-        _ => BuildResult::Failure(stderr_json_msgs, analysis),
+    (exit_code.ok() == Some(0), stderr_json_msgs, analysis)
+}
+
+/// The results of a rustc child process, sent back to the RLS as a single
+/// JSON line on the child's stdout.
+#[derive(Debug, Serialize, Deserialize)]
+struct ChildOutput {
+    success: bool,
+    messages: Vec<String>,
+    analysis: Option<Analysis>,
+}
+
+// Runs rustc in a child process, by re-running the RLS executable through the
+// `RLS_RUSTC_SHIM` path. A crashing compiler then doesn't take down the server
+// and the env vars are set for the child only, so no environment lock is
+// needed. Unsaved files are sent to the child over its stdin.
+fn rustc_out_of_process(args: &[String], envs: &HashMap<String, Option<OsString>>, changed: HashMap<PathBuf, String>, abort: Arc<AtomicBool>) -> Result<BuildResult, String> {
+    let rls_executable = rls_executable()
+        .map_err(|e| format!("couldn't find the RLS executable: {}", e))?;
+
+    let mut cmd = Command::new(rls_executable);
+    cmd.args(&args[1..]);
+    for (k, v) in envs {
+        match *v {
+            Some(ref v) => { cmd.env(k, v); }
+            None => { cmd.env_remove(k); }
+        }
+    }
+    cmd.env(::RUSTC_SHIM_ENV_VAR_NAME, "1");
+    cmd.env(::RUSTC_CHILD_ENV_VAR_NAME, "1");
+    // The child's stderr only receives what isn't a diagnostic (e.g., an ICE),
+    // so we pass it through to our own.
+    cmd.stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::inherit());
+
+    let mut child = cmd.spawn().map_err(|e| format!("couldn't spawn rustc: {}", e))?;
+
+    {
+        let mut stdin = child.stdin.take().unwrap();
+        if let Err(e) = serde_json::to_writer(&mut stdin, &changed) {
+            let _ = child.kill();
+            return Err(format!("couldn't send changed files to rustc: {}", e));
+        }
+    }
+
+    // Read the output on another thread, so that the child can't block on a
+    // full pipe while we wait for it to exit.
+    let mut stdout = child.stdout.take().unwrap();
+    let reader = thread::spawn(move || {
+        let mut output = String::new();
+        stdout.read_to_string(&mut output).map(|_| output)
+    });
+
+    let status = loop {
+        match child.try_wait() {
+            Ok(Some(status)) => break status,
+            Ok(None) => {}
+            Err(e) => return Err(format!("couldn't wait for rustc: {}", e)),
+        }
+        if abort.load(Ordering::SeqCst) {
+            trace!("rustc - aborted, killing child process");
+            let _ = child.kill();
+            let _ = child.wait();
+            return Ok(BuildResult::Squashed);
+        }
+        thread::sleep(Duration::from_millis(10));
+    };
+
+    let output = match reader.join() {
+        Ok(Ok(output)) => output,
+        _ => return Err("couldn't read rustc output".to_owned()),
+    };
+    let output: ChildOutput = output.lines().last()
+        .and_then(|line| serde_json::from_str(line).ok())
+        .ok_or_else(|| format!("rustc exited without results ({})", status))?;
+
+    let analysis = output.analysis.map(|analysis| vec![analysis]).unwrap_or(vec![]);
+    if output.success {
+        Ok(BuildResult::Success(output.messages, analysis))
+    } else {
+        Ok(BuildResult::Failure(output.messages, analysis))
+    }
+}
+
+// The RLS executable, which is run as the child process. Tests are run by a
+// test harness instead, so they use the RLS binary next to it (which Cargo
+// builds for the integration tests).
+fn rls_executable() -> io::Result<PathBuf> {
+    let exe = env::current_exe()?;
+    if !cfg!(test) {
+        return Ok(exe);
     }
+    // The harness is in `target/<profile>/deps`.
+    let bin_dir = exe.parent().and_then(|deps| deps.parent())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no binary directory"))?;
+    Ok(bin_dir.join(format!("rls{}", env::consts::EXE_SUFFIX)))
+}
+
+/// The entry point of a rustc child process, started by the RLS when
+/// `rustc_out_of_process` is set. Reads the unsaved files from stdin, runs
+/// the compiler with the process arguments and environment, and writes the
+/// diagnostics and save-analysis data as JSON to stdout.
+pub fn run_child() {
+    let mut input = String::new();
+    io::stdin().read_to_string(&mut input).expect("couldn't read changed files from the RLS");
+    let changed: HashMap<PathBuf, String> = serde_json::from_str(&input)
+        .expect("couldn't parse changed files from the RLS");
+
+    let args: Vec<String> = env::args().collect();
+    let (success, messages, analysis) = compile(args, changed, Arc::new(AtomicBool::new(false)),
+                                                Arc::new(AtomicBool::new(false)));
+
+    let output = ChildOutput { success, messages, analysis };
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    // Anything the compiler itself printed precedes our single line of output.
+    writeln!(stdout, "").unwrap();
+    serde_json::to_writer(&mut stdout, &output).expect("couldn't write rustc output");
+    writeln!(stdout, "").unwrap();
 }
 
 // Our compiler controller. We mostly delegate to the default rustc
@@ -92,13 +254,28 @@ pub fn rustc(vfs: &Vfs, args: &[String], envs: &HashMap<String, Option<OsString>
 struct RlsRustcCalls {
     default_calls: RustcDefaultCalls,
     analysis: Arc<Mutex<Option<Analysis>>>,
+    abort: Arc<AtomicBool>,
+    // Set when the compilation is stopped because of `abort`.
+    stopped: Arc<AtomicBool>,
 }
 
 impl RlsRustcCalls {
-    fn new(analysis: Arc<Mutex<Option<Analysis>>>) -> RlsRustcCalls {
+    fn new(analysis: Arc<Mutex<Option<Analysis>>>, abort: Arc<AtomicBool>, stopped: Arc<AtomicBool>) -> RlsRustcCalls {
         RlsRustcCalls {
             default_calls: RustcDefaultCalls,
             analysis: analysis,
+            abort: abort,
+            stopped: stopped,
+        }
+    }
+
+    // Whether the compilation should stop now, recording that it did.
+    fn should_stop(abort: &AtomicBool, stopped: &AtomicBool) -> bool {
+        if abort.load(Ordering::SeqCst) {
+            stopped.store(true, Ordering::SeqCst);
+            true
+        } else {
+            false
         }
     }
 }
@@ -144,7 +321,27 @@ impl<'a> CompilerCalls<'a> for RlsRustcCalls {
         result.keep_ast = true;
         let analysis = self.analysis.clone();
 
+        // Check between the compiler phases whether we should stop, since
+        // the build has been superseded by a newer one.
+        let (abort, stopped) = (self.abort.clone(), self.stopped.clone());
+        result.after_expand.callback = Box::new(move |state| {
+            if RlsRustcCalls::should_stop(&abort, &stopped) {
+                state.session.fatal("build aborted by the RLS");
+            }
+        });
+        let (abort, stopped) = (self.abort.clone(), self.stopped.clone());
+        result.after_hir_lowering.callback = Box::new(move |state| {
+            if RlsRustcCalls::should_stop(&abort, &stopped) {
+                state.session.fatal("build aborted by the RLS");
+            }
+        });
+
+        let (abort, stopped) = (self.abort.clone(), self.stopped.clone());
         result.after_analysis.callback = Box::new(move |state| {
+            if RlsRustcCalls::should_stop(&abort, &stopped) {
+                return;
+            }
+
             // There are two ways to move the data from rustc to the RLS, either
             // directly or by serialising and deserialising. We only want to do
             // the latter when there are compatibility issues between crates.
@@ -0,0 +1,199 @@
+// Copyright 2017 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Timing statistics of the most recent builds, to find out where the time
+//! goes when builds feel slow.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use super::BuildPriority;
+use super::dep_info;
+
+// Statistics of older builds are dropped.
+const MAX_RECORDED_BUILDS: usize = 20;
+
+/// Statistics of a single build request. Times are in milliseconds.
+#[derive(Clone, Debug, Serialize)]
+pub struct BuildStats {
+    /// Number of the build request, counting from the start of the RLS.
+    pub id: usize,
+    /// Priority the build was run (or requested) with.
+    pub priority: String,
+    /// Time between requesting and starting the build (including
+    /// `wait_to_build`), if it was started.
+    pub wait_time: Option<u64>,
+    /// Time spent running Cargo (including the crates it had the RLS
+    /// compile), if it was run.
+    pub cargo_time: Option<u64>,
+    /// Compilations performed by the RLS itself, in the order they finished.
+    pub units: Vec<UnitStats>,
+    /// Time the whole build took, if it was started.
+    pub build_time: Option<u64>,
+    /// Time spent reloading the analysis data after the build, if any.
+    pub analysis_reload_time: Option<u64>,
+    /// Whether the build was squashed by a more recent one.
+    pub squashed: bool,
+}
+
+/// Statistics of a single compiler invocation.
+#[derive(Clone, Debug, Serialize)]
+pub struct UnitStats {
+    /// Name of the compiled crate.
+    pub crate_name: String,
+    /// Time the compilation took, in milliseconds.
+    pub time: u64,
+}
+
+#[derive(Default)]
+struct Log {
+    next_id: usize,
+    builds: VecDeque<BuildStats>,
+}
+
+/// Statistics of the most recent builds.
+#[derive(Clone, Default)]
+pub struct BuildStatsLog(Arc<Mutex<Log>>);
+
+impl BuildStatsLog {
+    /// Construct an empty log.
+    pub fn new() -> BuildStatsLog {
+        BuildStatsLog::default()
+    }
+
+    /// Starts recording the statistics of a newly requested build.
+    pub fn record_build(&self, priority: BuildPriority) -> BuildRecorder {
+        let mut log = self.0.lock().unwrap();
+        let id = log.next_id;
+        log.next_id += 1;
+        if log.builds.len() == MAX_RECORDED_BUILDS {
+            log.builds.pop_front();
+        }
+        log.builds.push_back(BuildStats {
+            id,
+            priority: format!("{:?}", priority),
+            wait_time: None,
+            cargo_time: None,
+            units: vec![],
+            build_time: None,
+            analysis_reload_time: None,
+            squashed: false,
+        });
+
+        BuildRecorder {
+            log: self.clone(),
+            id,
+            requested: Instant::now(),
+        }
+    }
+
+    /// Returns the statistics of the most recent builds, oldest first.
+    pub fn recent(&self) -> Vec<BuildStats> {
+        self.0.lock().unwrap().builds.iter().cloned().collect()
+    }
+}
+
+/// Records the statistics of a single build.
+#[derive(Clone)]
+pub struct BuildRecorder {
+    log: BuildStatsLog,
+    id: usize,
+    requested: Instant,
+}
+
+impl BuildRecorder {
+    /// Updates the statistics of the build, unless these were already dropped
+    /// from the log.
+    pub fn update<F: FnOnce(&mut BuildStats)>(&self, f: F) {
+        let mut log = self.log.0.lock().unwrap();
+        if let Some(stats) = log.builds.iter_mut().find(|stats| stats.id == self.id) {
+            f(stats);
+        }
+    }
+
+    /// Returns the statistics recorded so far.
+    pub fn stats(&self) -> Option<BuildStats> {
+        let log = self.log.0.lock().unwrap();
+        log.builds.iter().find(|stats| stats.id == self.id).cloned()
+    }
+
+    /// Returns whether Cargo was run as part of the build.
+    pub fn ran_cargo(&self) -> bool {
+        self.stats().map_or(false, |stats| stats.cargo_time.is_some())
+    }
+
+    /// Records that the build has started with the given priority.
+    pub fn started(&self, priority: BuildPriority) {
+        let wait_time = millis(self.requested.elapsed());
+        self.update(|stats| {
+            stats.priority = format!("{:?}", priority);
+            stats.wait_time = Some(wait_time);
+        });
+    }
+
+    /// Records how long reloading the analysis data of the build took.
+    pub fn analysis_reloaded(&self, reload_time: Duration) {
+        let reload_time = millis(reload_time);
+        self.update(|stats| stats.analysis_reload_time = Some(reload_time));
+    }
+
+    /// Records that the build was squashed.
+    pub fn squashed(&self) {
+        self.update(|stats| stats.squashed = true);
+    }
+
+    /// Runs a compilation with the given rustc arguments, recording how long
+    /// it took.
+    pub fn time_unit<R, F: FnOnce() -> R>(&self, args: &[String], compile: F) -> R {
+        let start = Instant::now();
+        let result = compile();
+        let time = millis(start.elapsed());
+        let crate_name = dep_info::arg_value(args, "--crate-name").unwrap_or("<unknown>").to_owned();
+        self.update(|stats| stats.units.push(UnitStats { crate_name, time }));
+        result
+    }
+}
+
+/// Converts the duration to milliseconds.
+pub fn millis(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + u64::from(duration.subsec_nanos() / 1_000_000)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_record_builds() {
+        let log = BuildStatsLog::new();
+        let first = log.record_build(BuildPriority::Normal);
+        first.squashed();
+        let second = log.record_build(BuildPriority::Normal);
+        second.started(BuildPriority::Cargo);
+        let args = vec!["rustc".to_owned(), "--crate-name".to_owned(), "foo".to_owned()];
+        assert_eq!(second.time_unit(&args, || 42), 42);
+
+        let recent = log.recent();
+        assert_eq!(recent.len(), 2);
+        assert!(recent[0].squashed && recent[0].wait_time.is_none());
+        assert!(!recent[1].squashed && recent[1].wait_time.is_some());
+        assert_eq!(recent[1].priority, "Cargo");
+        assert_eq!(recent[1].units[0].crate_name, "foo");
+
+        for _ in 0..MAX_RECORDED_BUILDS {
+            log.record_build(BuildPriority::Normal);
+        }
+        assert_eq!(log.recent().len(), MAX_RECORDED_BUILDS);
+        // Statistics of dropped builds are no longer recorded.
+        assert!(first.stats().is_none());
+        first.squashed();
+    }
+}
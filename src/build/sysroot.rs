@@ -0,0 +1,222 @@
+// Copyright 2017 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Finding the sysroot of the toolchain a project is built with, which may be
+//! pinned with a `rust-toolchain` file or a rustup directory override, and
+//! checking that it matches the compiler the RLS is linked against.
+
+use std::env;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use super::rustc;
+
+const TOOLCHAIN_FILE_NAME: &'static str = "rust-toolchain";
+
+/// Returns the sysroot of the toolchain used for the project in `project_dir`.
+/// `SYSROOT` takes precedence, otherwise `rustc` is asked from within the
+/// project directory, so that rustup picks the project's toolchain.
+pub(super) fn resolve(project_dir: &Path) -> Option<String> {
+    resolve_with(env::var("SYSROOT").ok(), project_dir)
+}
+
+// `sysroot_override` is the value of `SYSROOT`, if it's set.
+fn resolve_with(sysroot_override: Option<String>, project_dir: &Path) -> Option<String> {
+    if let Some(sysroot) = sysroot_override {
+        return Some(sysroot);
+    }
+
+    let rustc_exe = env::var("RUSTC").unwrap_or("rustc".to_owned());
+    let mut cmd = Command::new(rustc_exe);
+    cmd.arg("--print").arg("sysroot").current_dir(project_dir);
+    // The toolchain the RLS itself was started with (e.g. by the rustup proxy)
+    // would otherwise take precedence over the project's one.
+    cmd.env_remove("RUSTUP_TOOLCHAIN").env_remove("MULTIRUST_TOOLCHAIN");
+
+    let sysroot = cmd.output()
+        .ok()
+        .and_then(|out| if out.status.success() { String::from_utf8(out.stdout).ok() } else { None })
+        .map(|s| s.trim().to_owned())
+        .and_then(|s| if s.is_empty() { None } else { Some(s) });
+    if sysroot.is_none() {
+        info!("couldn't query the sysroot from rustc in {:?}", project_dir);
+    }
+    sysroot.or_else(rustup_sysroot)
+}
+
+// The toolchain the RLS was started with by rustup (or multirust).
+fn rustup_sysroot() -> Option<String> {
+    let home = env::var("RUSTUP_HOME").or(env::var("MULTIRUST_HOME"));
+    let toolchain = env::var("RUSTUP_TOOLCHAIN").or(env::var("MULTIRUST_TOOLCHAIN"));
+    match (home, toolchain) {
+        (Ok(home), Ok(toolchain)) => Some(format!("{}/toolchains/{}", home, toolchain)),
+        _ => None,
+    }
+}
+
+/// Returns a JSON compiler message reporting that the toolchain in `sysroot`
+/// isn't the one the RLS is linked against, in which case the standard
+/// library can't be loaded. `None` if they match or either isn't known.
+pub(super) fn mismatch_message(sysroot: &str, project_dir: &Path) -> Option<String> {
+    let (linked_release, linked_hash) = rustc::linked_version()?;
+    mismatch_message_with(sysroot, project_dir, linked_release, linked_hash)
+}
+
+// Compares the toolchain in `sysroot` with the given linked compiler version.
+fn mismatch_message_with(sysroot: &str,
+                         project_dir: &Path,
+                         linked_release: &str,
+                         linked_hash: &str)
+                         -> Option<String> {
+    let (release, hash) = toolchain_version(sysroot)?;
+    if hash == linked_hash {
+        return None;
+    }
+
+    let message = format!("the project's toolchain (rustc {} ({}), in {}) doesn't match the one \
+                           the RLS was built with (rustc {} ({})); use the RLS of the project's \
+                           toolchain, or point the `sysroot` option to a matching one",
+                          release, short_hash(&hash), sysroot, linked_release, short_hash(linked_hash));
+    info!("{}", message);
+
+    let file_name = toolchain_file(project_dir);
+    Some(json!({
+        "message": message,
+        "code": null,
+        "level": "error",
+        "spans": [{
+            "file_name": file_name,
+            "byte_start": 0,
+            "byte_end": 0,
+            "line_start": 1,
+            "line_end": 1,
+            "column_start": 1,
+            "column_end": 1,
+            "is_primary": true,
+            "text": [],
+            "label": null,
+            "suggested_replacement": null,
+            "expansion": null
+        }],
+        "children": [],
+        "rendered": null
+    }).to_string())
+}
+
+/// Returns the commit hash of the toolchain the project in `project_dir` is
+/// built with, which is in `sysroot` if given (e.g. from the config).
+pub fn toolchain_hash(project_dir: &Path, sysroot: Option<&str>) -> Option<String> {
+    let sysroot = match sysroot {
+        Some(sysroot) => sysroot.to_owned(),
+        None => resolve(project_dir)?,
+    };
+    toolchain_version(&sysroot).map(|(_, hash)| hash)
+}
+
+// The release and commit hash of the compiler in `sysroot`.
+fn toolchain_version(sysroot: &str) -> Option<(String, String)> {
+    let rustc_exe = Path::new(sysroot).join("bin").join(format!("rustc{}", env::consts::EXE_SUFFIX));
+    let output = Command::new(&rustc_exe).arg("-vV").output().ok()?;
+    let version = String::from_utf8(output.stdout).ok()?;
+    let release = version_field(&version, "release")?;
+    let hash = version_field(&version, "commit-hash")?;
+    Some((release.to_owned(), hash.to_owned()))
+}
+
+// Reads a field of the `rustc -vV` output, e.g. `commit-hash: 1a2b3c...`.
+fn version_field<'a>(version: &'a str, name: &str) -> Option<&'a str> {
+    version.lines()
+        .filter_map(|line| {
+            let mut parts = line.splitn(2, ": ");
+            match (parts.next(), parts.next()) {
+                (Some(key), Some(value)) if key == name => Some(value.trim()),
+                _ => None,
+            }
+        })
+        .next()
+}
+
+fn short_hash(hash: &str) -> &str {
+    &hash[..hash.len().min(9)]
+}
+
+// The file a mismatch is reported in: the `rust-toolchain` file pinning the
+// toolchain if there is one, otherwise the project's manifest.
+fn toolchain_file(project_dir: &Path) -> PathBuf {
+    let mut dir = Some(project_dir);
+    while let Some(current) = dir {
+        let file = current.join(TOOLCHAIN_FILE_NAME);
+        if file.is_file() {
+            return file;
+        }
+        dir = current.parent();
+    }
+    let manifest = project_dir.join("Cargo.toml");
+    if manifest.is_file() {
+        return manifest;
+    }
+    super::find_rust_project(project_dir).unwrap_or(manifest)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json;
+    use std::fs::{self, File};
+    use std::io::Write;
+
+    #[test]
+    fn test_version_field() {
+        let version = "rustc 1.23.0-nightly (5041b3bb3 2017-11-19)\n\
+                       binary: rustc\n\
+                       commit-hash: 5041b3bb3d953a14f32b15d1e41341c629acae12\n\
+                       commit-date: 2017-11-19\n\
+                       host: x86_64-unknown-linux-gnu\n\
+                       release: 1.23.0-nightly\n\
+                       LLVM version: 4.0\n";
+        assert_eq!(version_field(version, "release"), Some("1.23.0-nightly"));
+        let hash = version_field(version, "commit-hash").unwrap();
+        assert_eq!(short_hash(hash), "5041b3bb3");
+        assert_eq!(version_field(version, "commit"), None);
+    }
+
+    #[test]
+    fn test_resolve() {
+        let cwd = env::current_dir().unwrap();
+        assert_eq!(resolve_with(Some("/custom/sysroot".to_owned()), &cwd),
+                   Some("/custom/sysroot".to_owned()));
+
+        let sysroot = resolve_with(None, &cwd).expect("no sysroot");
+        assert!(Path::new(&sysroot).join("bin").join(format!("rustc{}", env::consts::EXE_SUFFIX)).is_file());
+    }
+
+    #[test]
+    fn test_mismatch_message() {
+        let sysroot = resolve_with(None, &env::current_dir().unwrap()).expect("no sysroot");
+        let (_, hash) = toolchain_version(&sysroot).unwrap();
+        assert_eq!(toolchain_hash(Path::new("/"), Some(&sysroot[..])), Some(hash.clone()));
+
+        let dir = env::current_dir().unwrap().join("target").join("tests").join("sysroot_mismatch");
+        fs::create_dir_all(&dir).unwrap();
+        File::create(dir.join(TOOLCHAIN_FILE_NAME)).unwrap().write_all(b"nightly\n").unwrap();
+
+        assert_eq!(mismatch_message_with(&sysroot, &dir, "1.0.0-linked", &hash), None);
+
+        let message = mismatch_message_with(&sysroot, &dir, "1.0.0-linked", "0123456789abcdef")
+            .expect("no mismatch reported");
+        let message: serde_json::Value = serde_json::from_str(&message).unwrap();
+        assert_eq!(message["level"].as_str(), Some("error"));
+        assert!(message["message"].as_str().unwrap().contains("(rustc 1.0.0-linked (012345678))"));
+        assert_eq!(message["spans"][0]["file_name"].as_str(),
+                   dir.join(TOOLCHAIN_FILE_NAME).to_str());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
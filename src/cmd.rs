@@ -101,6 +101,9 @@ pub fn run() {
                 let insert_spaces : bool = bits.next().unwrap_or("true").parse().expect("Insert spaces should be 'true' or 'false'");;
                 range_format(file_name, start_row, start_col, end_row, end_col, tab_size, insert_spaces).to_string()
             }
+            "build_plan" => {
+                build_plan().to_string()
+            }
             "h" | "help" => {
                 help();
                 continue;
@@ -211,6 +214,14 @@ fn range_format<'a>(file_name: &str, start_row: u64, start_col: u64, end_row: u6
     }
 }
 
+fn build_plan<'a>() -> Request<'a, requests::BuildPlan> {
+    Request {
+        id: next_id(),
+        params: NoParams {},
+        _action: PhantomData,
+    }
+}
+
 fn shutdown<'a>() -> Request<'a, server::ShutdownRequest<'a>> {
     Request {
         id: next_id(),
@@ -347,4 +358,8 @@ fn help() {
     println!("    range_format  file_name start_line start_col end_line end_col [tab_size [insert_spaces]]");
     println!("                  textDocument/rangeFormatting");
     println!("                  tab_size defaults to 4 and insert_spaces to 'true'");
+    println!("");
+    println!("    build_plan");
+    println!("                  rls/buildPlan");
+    println!("                  prints the cached build plan");
 }
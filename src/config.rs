@@ -13,9 +13,11 @@
 
 use build;
 
+use std::collections::HashMap;
 use std::fmt::Debug;
-use std::io::sink;
+use std::io::{self, sink};
 use std::path::{Path, PathBuf};
+use std::sync::Mutex;
 
 use cargo::CargoResult;
 use cargo::util::important_paths;
@@ -111,18 +113,51 @@ pub struct Config {
     pub rustflags: Option<String>,
     pub build_lib: Inferrable<bool>,
     pub build_bin: Inferrable<Option<String>>,
+    /// Check every crate target in the package (lib, bins, tests, examples
+    /// and benches) rather than a single one. Overrides `build_lib` and
+    /// `build_bin`. Default: false
+    pub all_targets: bool,
     pub cfg_test: bool,
+    /// Build the primary crate both with and without `cfg(test)` and merge
+    /// the analysis and diagnostics of both. Overrides `cfg_test`. Not
+    /// supported with `workspace_mode` or `full_analysis_crates`, unless
+    /// `all_targets` is set. Default: false
+    pub cfg_test_both: bool,
     pub unstable_features: bool,
     pub wait_to_build: u64,
     pub show_warnings: bool,
+    /// Clear the diagnostics of a file when it's closed and only publish the
+    /// diagnostics of open files. Default: false
+    pub clear_diagnostics_on_close: bool,
     pub goto_def_racer_fallback: bool,
     pub workspace_mode: bool,
     pub analyze_package: Option<String>,
+    /// Names of the dependency packages (e.g. path dependencies edited
+    /// alongside the project) which get full analysis and diagnostics like
+    /// the primary crate, rather than only the analysis of their public API.
+    /// Default: []
+    pub full_analysis_crates: Vec<String>,
+    /// Maximum number of crates compiled at the same time when rebuilding
+    /// from the build plan with `rustc_out_of_process`. Default: number of
+    /// CPUs
+    pub jobs: Option<u32>,
+    /// Run each rustc invocation in a child process rather than in the RLS
+    /// process itself. Default: false
+    pub rustc_out_of_process: bool,
+    /// Send the statistics of every build (see `rls/buildStats`) to the
+    /// client as a `telemetry/event` notification. Default: false
+    pub build_telemetry: bool,
     /// Clear the RUST_LOG env variable before calling rustc/cargo? Default: true
     pub clear_env_rust_log: bool,
     /// Build the project only when a file got saved and not on file change. Default: false
     pub build_on_save: bool,
+    /// Format a document with rustfmt when it's saved (requires the client to
+    /// support `textDocument/willSaveWaitUntil`). Default: false
+    pub format_on_save: bool,
     pub use_crate_blacklist: bool,
+    /// Changes to the default list of crates which are excluded from the
+    /// analysis when `use_crate_blacklist` is set.
+    pub crate_blacklist: CrateBlacklist,
     /// Cargo target dir. If set overrides the default one.
     #[serde(skip_deserializing, skip_serializing)]
     pub target_dir: Option<PathBuf>,
@@ -139,16 +174,25 @@ impl Default for Config {
             rustflags: None,
             build_lib: Inferrable::Inferred(false),
             build_bin: Inferrable::Inferred(None),
+            all_targets: false,
             cfg_test: false,
+            cfg_test_both: false,
             unstable_features: false,
             wait_to_build: DEFAULT_WAIT_TO_BUILD,
             show_warnings: true,
+            clear_diagnostics_on_close: false,
             goto_def_racer_fallback: false,
             workspace_mode: false,
             analyze_package: None,
+            full_analysis_crates: vec![],
+            jobs: None,
+            rustc_out_of_process: false,
+            build_telemetry: false,
             clear_env_rust_log: true,
             build_on_save: false,
+            format_on_save: false,
             use_crate_blacklist: true,
+            crate_blacklist: CrateBlacklist::default(),
             target_dir: None,
             features: vec![],
             all_features: false,
@@ -187,6 +231,37 @@ impl Config {
             self.workspace_mode = false;
             self.analyze_package = None;
         }
+
+        // Only a single crate target or every crate target (which are also
+        // checked as test harnesses) can be built in both configurations.
+        if self.cfg_test_both && self.uses_build_plan() && (self.workspace_mode || !self.all_targets) {
+            eprintln!("`cfg_test_both` setting can't be used with `workspace_mode` or \
+                       `full_analysis_crates` without `all_targets`; using `cfg_test` instead");
+            self.cfg_test_both = false;
+            self.cfg_test = true;
+        }
+    }
+
+    /// Whether primary crates are compiled in-process during the Cargo
+    /// routine and their compiler invocations cached in the build plan, which
+    /// is needed when there is more than a single primary crate target.
+    pub fn uses_build_plan(&self) -> bool {
+        self.workspace_mode || self.all_targets || !self.full_analysis_crates.is_empty()
+    }
+
+    /// Names of the crates which are excluded from the analysis (the default
+    /// ones, changed by `crate_blacklist`), or none if `use_crate_blacklist`
+    /// isn't set.
+    pub fn crate_blacklist(&self) -> Vec<String> {
+        if !self.use_crate_blacklist {
+            return vec![];
+        }
+
+        ::CRATE_BLACKLIST.iter()
+            .map(|name| name.to_string())
+            .filter(|name| !self.crate_blacklist.remove.contains(name))
+            .chain(self.crate_blacklist.add.iter().cloned())
+            .collect()
     }
 
     /// Is this config incomplete, and needs additional values to be inferred?
@@ -200,6 +275,13 @@ impl Config {
 
     /// Infer default values for the given project directory.
     pub fn infer_defaults(&mut self, project_dir: &Path) -> CargoResult<()> {
+        // Crates of projects without Cargo are all built, so there's no
+        // crate target to choose
+        if let Some(project_file) = build::find_rust_project(project_dir) {
+            trace!("infer_config_defaults: project described by {:?}, nothing to infer", project_file);
+            return Ok(());
+        }
+
         // Note that this may not be equal build_dir when inside a workspace member
         let manifest_path = important_paths::find_root_manifest_for_wd(None, project_dir)?;
         trace!("root manifest_path: {:?}", &manifest_path);
@@ -270,23 +352,33 @@ impl Config {
     }
 }
 
+/// Crates added to and removed from the default crate blacklist.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct CrateBlacklist {
+    /// Crates to exclude from the analysis as well.
+    pub add: Vec<String>,
+    /// Crates to analyse even though they're excluded by default.
+    pub remove: Vec<String>,
+}
+
 /// A rustfmt config (typically specified via rustfmt.toml)
 /// The FmtConfig is not an exact translation of the config
 /// rustfmt generates from the user's toml file, since when
 /// using rustfmt with rls certain configuration options are
 /// always used. See `FmtConfig::set_rls_options`
+#[derive(Clone)]
 pub struct FmtConfig(RustfmtConfig);
 
 impl FmtConfig {
-    /// Look for `.rustmt.toml` or `rustfmt.toml` in `path`, falling back
-    /// to the default config if neither exist
-    pub fn from(path: &Path) -> FmtConfig {
-        if let Ok((config, _)) = RustfmtConfig::from_resolved_toml_path(path) {
-            let mut config = FmtConfig(config);
-            config.set_rls_options();
-            return config;
-        }
-        FmtConfig::default()
+    /// Look for `.rustmt.toml` or `rustfmt.toml` in `path` or its ancestors,
+    /// using the default config if neither exist. Fails if the config file
+    /// can't be read or parsed.
+    pub fn resolve(path: &Path) -> io::Result<FmtConfig> {
+        let (config, _) = RustfmtConfig::from_resolved_toml_path(path)?;
+        let mut config = FmtConfig(config);
+        config.set_rls_options();
+        Ok(config)
     }
 
     /// Return an immutable borrow of the config, will always
@@ -310,3 +402,113 @@ impl Default for FmtConfig {
         config
     }
 }
+
+/// Rustfmt configs for formatting files, resolved for the directory of each
+/// file (so that nested crates can have their own `rustfmt.toml`) and cached
+/// until a config file changes.
+#[derive(Default)]
+pub struct FmtConfigCache {
+    configs: Mutex<HashMap<PathBuf, FmtConfig>>,
+}
+
+impl FmtConfigCache {
+    /// Construct an empty cache.
+    pub fn new() -> FmtConfigCache {
+        FmtConfigCache::default()
+    }
+
+    /// Returns the config for formatting `file`. If its config file can't be
+    /// read, the default config is used instead and the error is returned as
+    /// well, the first time the config is resolved.
+    pub fn get(&self, file: &Path) -> (FmtConfig, Option<String>) {
+        let dir = file.parent().unwrap_or(file);
+        let mut configs = self.configs.lock().unwrap();
+        if let Some(config) = configs.get(dir) {
+            return (config.clone(), None);
+        }
+
+        let (config, error) = match FmtConfig::resolve(dir) {
+            Ok(config) => (config, None),
+            Err(e) => {
+                let error = format!("couldn't read the rustfmt config for {}: {}", dir.display(), e);
+                debug!("{}", error);
+                (FmtConfig::default(), Some(error))
+            }
+        };
+        configs.insert(dir.to_owned(), config.clone());
+        (config, error)
+    }
+
+    /// Drops the cached configs, so that they're resolved again.
+    pub fn invalidate(&self) {
+        self.configs.lock().unwrap().clear();
+    }
+}
+
+/// Returns whether the file is a rustfmt config file.
+pub fn is_fmt_config(path: &Path) -> bool {
+    path.file_name().map_or(false, |name| name == "rustfmt.toml" || name == ".rustfmt.toml")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_crate_blacklist() {
+        let mut config = Config::default();
+        config.crate_blacklist.add = vec!["my_generated_crate".to_owned()];
+        config.crate_blacklist.remove = vec!["serde".to_owned()];
+
+        let blacklist = config.crate_blacklist();
+        assert!(blacklist.contains(&"libc".to_owned()));
+        assert!(blacklist.contains(&"my_generated_crate".to_owned()));
+        assert!(!blacklist.contains(&"serde".to_owned()));
+
+        config.use_crate_blacklist = false;
+        assert!(config.crate_blacklist().is_empty());
+    }
+
+    #[test]
+    fn test_normalise_cfg_test_both() {
+        let mut config = Config::default();
+        config.cfg_test_both = true;
+        config.all_targets = true;
+        config.normalise();
+        assert!(config.cfg_test_both);
+
+        config.all_targets = false;
+        config.full_analysis_crates = vec!["dep".to_owned()];
+        config.normalise();
+        assert!(!config.cfg_test_both);
+        assert!(config.cfg_test);
+    }
+
+    #[test]
+    fn test_fmt_config_cache() {
+        let dir = ::std::env::current_dir().unwrap().join("test_data").join("fmt_config");
+        let cache = FmtConfigCache::new();
+
+        let (config, error) = cache.get(&dir.join("lib.rs"));
+        assert!(error.is_none());
+        assert_eq!(config.get_rustfmt_config().max_width(), 80);
+        assert!(!config.get_rustfmt_config().hard_tabs());
+
+        // The nearest config file is used.
+        let (config, error) = cache.get(&dir.join("nested").join("lib.rs"));
+        assert!(error.is_none());
+        assert!(config.get_rustfmt_config().hard_tabs());
+
+        // Errors are only reported when the config is resolved.
+        let (config, error) = cache.get(&dir.join("broken").join("lib.rs"));
+        assert!(error.is_some());
+        assert_eq!(config.get_rustfmt_config().max_width(), FmtConfig::default().get_rustfmt_config().max_width());
+        assert!(cache.get(&dir.join("broken").join("lib.rs")).1.is_none());
+        cache.invalidate();
+        assert!(cache.get(&dir.join("broken").join("lib.rs")).1.is_some());
+
+        assert!(is_fmt_config(&dir.join("rustfmt.toml")));
+        assert!(is_fmt_config(&dir.join(".rustfmt.toml")));
+        assert!(!is_fmt_config(&dir.join("Cargo.toml")));
+    }
+}
@@ -68,12 +68,16 @@ const COMPILER_TIMEOUT: u64 = 1500;
 #[cfg(test)]
 const COMPILER_TIMEOUT: u64 = 3_600_000;
 
+// Crates excluded from the analysis by default, see `Config::crate_blacklist`.
 const CRATE_BLACKLIST: [&'static str; 10] = [
     "libc", "typenum", "alloc", "idna", "openssl", "libunicode_normalization", "serde",
     "serde_json", "librustc_serialize", "libunicode_segmentation",
 ];
 
 const RUSTC_SHIM_ENV_VAR_NAME: &'static str = "RLS_RUSTC_SHIM";
+// Set alongside `RLS_RUSTC_SHIM` when the RLS runs rustc as its own child
+// process (see the `rustc_out_of_process` option).
+const RUSTC_CHILD_ENV_VAR_NAME: &'static str = "RLS_RUSTC_CHILD";
 
 type Span = span::Span<span::ZeroIndexed>;
 
@@ -83,7 +87,11 @@ pub fn main() {
     env_logger::init().unwrap();
 
     if env::var(RUSTC_SHIM_ENV_VAR_NAME).map(|v| v != "0").unwrap_or(false) {
-        rustc_shim::run();
+        if env::var(RUSTC_CHILD_ENV_VAR_NAME).is_ok() {
+            build::run_rustc_child();
+        } else {
+            rustc_shim::run();
+        }
         return;
     }
 
@@ -137,6 +137,33 @@ pub trait Output: Sync + Send + Clone + 'static {
     fn notify(&self, notification: NotificationMessage) {
         self.response(serde_json::to_string(&notification).unwrap());
     }
+
+    /// Send a `window/showMessage` notification, which displays the message to
+    /// the user.
+    fn show_message<M: Into<String>>(&self, typ: MessageType, message: M) {
+        let params = ShowMessageParams {
+            typ,
+            message: message.into(),
+        };
+        let output = format!("{{\"jsonrpc\":\"2.0\",\"method\":\"window/showMessage\",\"params\":{}}}",
+                             serde_json::to_string(&params).unwrap());
+        self.response(output);
+    }
+
+    /// Send a `telemetry/event` notification with the given data along the
+    /// output.
+    fn telemetry_event<D: ::serde::Serialize + fmt::Debug>(&self, data: &D) {
+        let data = match serde_json::to_string(data) {
+            Ok(data) => data,
+            Err(e) => {
+                debug!("Could not serialize data for telemetry event: {:?} ({:?})", data, e);
+                return;
+            }
+        };
+
+        let output = format!("{{\"jsonrpc\":\"2.0\",\"method\":\"telemetry/event\",\"params\":{}}}", data);
+        self.response(output);
+    }
 }
 
 /// An output that sends notifications and responses on `stdout`.
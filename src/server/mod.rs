@@ -257,6 +257,15 @@ fn get_root_path(params: &InitializeParams) -> PathBuf {
     })
 }
 
+// Rename is registered dynamically if the client supports it, as the static
+// capability can't announce `prepareRename` support.
+fn supports_dynamic_rename(params: &InitializeParams) -> bool {
+    params.capabilities.text_document.as_ref()
+        .and_then(|text_document| text_document.rename.as_ref())
+        .and_then(|rename| rename.dynamic_registration)
+        .unwrap_or(false)
+}
+
 impl<'a> RequestAction<'a> for InitializeRequest {
     type Response = NoResponse;
     fn handle<O: Output>(&mut self, id: usize, params: Self::Params, ctx: &mut ActionContext, out: O) -> Result<NoResponse, ()> {
@@ -267,6 +276,7 @@ impl<'a> RequestAction<'a> for InitializeRequest {
             .unwrap_or_default();
 
         trace!("init: {:?}", init_options);
+        let dynamic_rename = supports_dynamic_rename(&params);
 
         let result = InitializeResult {
             capabilities: ServerCapabilities {
@@ -286,20 +296,20 @@ impl<'a> RequestAction<'a> for InitializeRequest {
                 execute_command_provider: Some(ExecuteCommandOptions {
                     commands: vec!["rls.applySuggestion".to_owned()],
                 }),
-                rename_provider: Some(true),
-                // These are supported if the `unstable_features` option is set.
-                // We'll update these capabilities dynamically when we get config
-                // info from the client.
-                document_range_formatting_provider: Some(false),
+                rename_provider: if dynamic_rename { None } else { Some(true) },
+                document_range_formatting_provider: Some(true),
+                document_on_type_formatting_provider: Some(DocumentOnTypeFormattingOptions {
+                    first_trigger_character: "}".to_owned(),
+                    more_trigger_character: Some(vec![";".to_owned(), "\n".to_owned()]),
+                }),
 
                 code_lens_provider: None,
-                document_on_type_formatting_provider: None,
                 signature_help_provider: None,
             }
         };
         out.success(id, &result);
 
-        ctx.init(get_root_path(&params), &init_options, out);
+        ctx.init(get_root_path(&params), &init_options, dynamic_rename, out);
 
         Ok(NoResponse)
     }
@@ -405,6 +415,7 @@ impl<O: Output> LsService<O> {
                 ExitNotification,
                 notifications::Initialized,
                 notifications::DidOpen,
+                notifications::DidClose,
                 notifications::DidChange,
                 notifications::DidSave,
                 notifications::DidChangeConfiguration,
@@ -418,6 +429,7 @@ impl<O: Output> LsService<O> {
                 requests::Completion,
                 requests::ResolveCompletion,
                 requests::Rename,
+                requests::PrepareRename,
                 requests::DocumentHighlight,
                 requests::ExecuteCommand,
                 requests::CodeAction,
@@ -427,6 +439,10 @@ impl<O: Output> LsService<O> {
                 requests::WorkspaceSymbol,
                 requests::Formatting,
                 requests::RangeFormatting,
+                requests::OnTypeFormatting,
+                requests::WillSaveWaitUntil,
+                requests::BuildPlan,
+                requests::BuildStats,
                 requests::Hover;
         );
         Ok(())
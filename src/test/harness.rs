@@ -31,6 +31,8 @@ pub struct Environment {
     pub config: Option<Config>,
     pub cache: Cache,
     pub target_path: PathBuf,
+    // Shared with the server, to check the contents it sees.
+    pub vfs: Arc<vfs::Vfs>,
 }
 
 impl Environment {
@@ -61,6 +63,7 @@ impl Environment {
             config: Some(config),
             cache,
             target_path,
+            vfs: Arc::new(vfs::Vfs::new()),
         }
     }
 }
@@ -74,7 +77,7 @@ impl Environment {
     // Initialize and run the internals of an LS protocol RLS server.
     pub fn mock_server(&mut self, messages: Vec<String>) -> (ls_server::LsService<RecordOutput>, LsResultList) {
         let analysis = Arc::new(analysis::AnalysisHost::new(analysis::Target::Debug));
-        let vfs = Arc::new(vfs::Vfs::new());
+        let vfs = self.vfs.clone();
         let config = Arc::new(Mutex::new(self.config.take().unwrap()));
         let reader = Box::new(MockMsgReader::new(messages));
         let output = RecordOutput::new();
@@ -165,6 +168,7 @@ impl ls_server::Output for RecordOutput {
 pub struct ExpectedMessage {
     id: Option<u64>,
     contains: Vec<String>,
+    not_contains: Vec<String>,
 }
 
 impl ExpectedMessage {
@@ -172,6 +176,7 @@ impl ExpectedMessage {
         ExpectedMessage {
             id: id,
             contains: vec![],
+            not_contains: vec![],
         }
     }
 
@@ -179,6 +184,11 @@ impl ExpectedMessage {
         self.contains.push(s.to_owned());
         self
     }
+
+    pub fn expect_not_contains(&mut self, s: &str) -> &mut ExpectedMessage {
+        self.not_contains.push(s.to_owned());
+        self
+    }
 }
 
 pub fn expect_messages(results: LsResultList, expected: &[&ExpectedMessage]) {
@@ -205,6 +215,9 @@ pub fn expect_messages(results: LsResultList, expected: &[&ExpectedMessage]) {
         for c in expected.contains.iter() {
             found.find(c).expect(&format!("Could not find `{}` in `{}`", c, found));
         }
+        for c in expected.not_contains.iter() {
+            assert!(found.find(c).is_none(), "Unexpectedly found `{}` in `{}`", c, found);
+        }
     }
 
     *results = vec![];
@@ -14,9 +14,9 @@
 mod harness;
 
 use analysis;
-use actions::requests;
+use actions::{notifications, requests};
 use config::{Config, Inferrable};
-use server::{self as ls_server, Request, ShutdownRequest, NoParams};
+use server::{self as ls_server, Notification, Request, ShutdownRequest, NoParams};
 use jsonrpc_core;
 use vfs;
 
@@ -65,6 +65,13 @@ pub fn request<'a, T: ls_server::RequestAction<'a>>(id: usize, params: T::Params
     }
 }
 
+pub fn notification<'a, T: ls_server::NotificationAction<'a>>(params: T::Params) -> Notification<'a, T> {
+    Notification {
+        params,
+        _action: PhantomData,
+    }
+}
+
 #[test]
 fn test_shutdown() {
     let mut env = Environment::new("common");
@@ -263,6 +270,42 @@ fn test_find_all_refs_no_cfg_test() {
                                                                      .expect_contains(r#"{"start":{"line":22,"character":15},"end":{"line":22,"character":18}}"#)]);
 }
 
+#[test]
+fn test_find_all_refs_cfg_test_both() {
+    let mut env = Environment::new("common");
+
+    let source_file_path = Path::new("src").join("main.rs");
+
+    let root_path = env.cache.abs_path(Path::new("."));
+    let url = Url::from_file_path(env.cache.abs_path(&source_file_path)).expect("couldn't convert file path to URL");
+
+    let messages = vec![
+        initialize(0, root_path.as_os_str().to_str().map(|x| x.to_owned())).to_string(),
+        request::<requests::References>(42, ReferenceParams {
+            text_document: TextDocumentIdentifier::new(url),
+            position: env.cache.mk_ls_position(src(&source_file_path, 10, "Bar")),
+            context: ReferenceContext { include_declaration: true }
+        }).to_string(),
+    ];
+
+    env.with_config(|c| c.cfg_test_both = true);
+    let (mut server, results) = env.mock_server(messages);
+    // Initialize and build.
+    assert_eq!(ls_server::LsService::handle_message(&mut server),
+               ls_server::ServerStateChange::Continue);
+    expect_messages(results.clone(), &[ExpectedMessage::new(Some(0)).expect_contains("capabilities"),
+                                       ExpectedMessage::new(None).expect_contains("beginBuild"),
+                                       ExpectedMessage::new(None).expect_contains("diagnosticsBegin"),
+                                       ExpectedMessage::new(None).expect_contains("diagnosticsEnd")]);
+
+    assert_eq!(ls_server::LsService::handle_message(&mut server),
+               ls_server::ServerStateChange::Continue);
+    // References both in the `cfg(test)` only function and in `main`.
+    expect_messages(results.clone(), &[ExpectedMessage::new(Some(42)).expect_contains(r#"{"start":{"line":9,"character":7},"end":{"line":9,"character":10}}"#)
+                                                                     .expect_contains(r#"{"start":{"line":15,"character":14},"end":{"line":15,"character":17}}"#)
+                                                                     .expect_contains(r#"{"start":{"line":23,"character":15},"end":{"line":23,"character":18}}"#)]);
+}
+
 #[test]
 fn test_borrow_error() {
     let mut env = Environment::new("borrow_error");
@@ -283,6 +326,99 @@ fn test_borrow_error() {
                                        ExpectedMessage::new(None).expect_contains("diagnosticsEnd")]);
 }
 
+#[test]
+fn test_rustc_out_of_process() {
+    let mut env = Environment::new("borrow_error");
+
+    let root_path = env.cache.abs_path(Path::new("."));
+    let messages = vec![
+        initialize(0, root_path.as_os_str().to_str().map(|x| x.to_owned())).to_string()
+    ];
+
+    env.with_config(|c| c.rustc_out_of_process = true);
+    let (mut server, results) = env.mock_server(messages);
+    // Initialize and build, with diagnostics coming from the child process.
+    assert_eq!(ls_server::LsService::handle_message(&mut server),
+               ls_server::ServerStateChange::Continue);
+    expect_messages(results.clone(), &[ExpectedMessage::new(Some(0)).expect_contains("capabilities"),
+                                       ExpectedMessage::new(None).expect_contains("beginBuild"),
+                                       ExpectedMessage::new(None).expect_contains("diagnosticsBegin"),
+                                       ExpectedMessage::new(None).expect_contains(r#""message":"cannot borrow `x` as mutable more than once at a time""#),
+                                       ExpectedMessage::new(None).expect_contains("diagnosticsEnd")]);
+}
+
+#[test]
+fn test_clear_diagnostics_on_close() {
+    use std::fs::File;
+    use std::io::Read;
+
+    // The contents the server sees.
+    fn vfs_text(vfs: &vfs::Vfs, path: &Path) -> String {
+        match vfs.load_file(path).unwrap() {
+            vfs::FileContents::Text(text) => text,
+            _ => panic!("{:?} isn't a text file", path),
+        }
+    }
+
+    let mut env = Environment::new("borrow_error");
+
+    let source_file_path = Path::new("src").join("main.rs");
+
+    let root_path = env.cache.abs_path(Path::new("."));
+    let file_path = env.cache.abs_path(&source_file_path);
+    let url = Url::from_file_path(&file_path).expect("couldn't convert file path to URL");
+    let mut disk_text = String::new();
+    File::open(&file_path).unwrap().read_to_string(&mut disk_text).unwrap();
+    let unsaved_text = format!("{}// unsaved\n", disk_text);
+    let open = |text: &str| notification::<notifications::DidOpen>(DidOpenTextDocumentParams {
+        text_document: TextDocumentItem {
+            uri: url.clone(),
+            language_id: "rust".to_owned(),
+            version: 1,
+            text: text.to_owned(),
+        },
+    }).to_string();
+
+    let messages = vec![
+        initialize(0, root_path.as_os_str().to_str().map(|x| x.to_owned())).to_string(),
+        open(&unsaved_text),
+        notification::<notifications::DidClose>(DidCloseTextDocumentParams {
+            text_document: TextDocumentIdentifier::new(url.clone()),
+        }).to_string(),
+        open(&disk_text),
+    ];
+
+    env.with_config(|c| c.clear_diagnostics_on_close = true);
+    let (mut server, results) = env.mock_server(messages);
+    // Initialize and build. No file is open, so no diagnostics are published.
+    assert_eq!(ls_server::LsService::handle_message(&mut server),
+               ls_server::ServerStateChange::Continue);
+    expect_messages(results.clone(), &[ExpectedMessage::new(Some(0)).expect_contains("capabilities"),
+                                       ExpectedMessage::new(None).expect_contains("beginBuild"),
+                                       ExpectedMessage::new(None).expect_contains("diagnosticsBegin"),
+                                       ExpectedMessage::new(None).expect_contains("diagnosticsEnd")]);
+
+    // Opening the file publishes the diagnostics of the last build.
+    assert_eq!(ls_server::LsService::handle_message(&mut server),
+               ls_server::ServerStateChange::Continue);
+    expect_messages(results.clone(), &[ExpectedMessage::new(None).expect_contains("publishDiagnostics")
+                                                                 .expect_contains("cannot borrow `x` as mutable")]);
+    assert_eq!(vfs_text(&env.vfs, &file_path), unsaved_text);
+
+    // Closing it clears them and discards the unsaved contents.
+    assert_eq!(ls_server::LsService::handle_message(&mut server),
+               ls_server::ServerStateChange::Continue);
+    expect_messages(results.clone(), &[ExpectedMessage::new(None).expect_contains("publishDiagnostics")
+                                                                 .expect_contains(r#""diagnostics":[]"#)]);
+    assert_eq!(vfs_text(&env.vfs, &file_path), disk_text);
+
+    // Reopening it publishes them again.
+    assert_eq!(ls_server::LsService::handle_message(&mut server),
+               ls_server::ServerStateChange::Continue);
+    expect_messages(results.clone(), &[ExpectedMessage::new(None).expect_contains("publishDiagnostics")
+                                                                 .expect_contains("cannot borrow `x` as mutable")]);
+}
+
 #[test]
 fn test_highlight() {
     let mut env = Environment::new("common");
@@ -349,6 +485,205 @@ fn test_rename() {
                                                                      .expect_contains(r#"{"changes""#),]);
 }
 
+#[test]
+fn test_rename_invalid_name() {
+    let mut env = Environment::new("common");
+
+    let source_file_path = Path::new("src").join("main.rs");
+
+    let root_path = env.cache.abs_path(Path::new("."));
+    let url = Url::from_file_path(env.cache.abs_path(&source_file_path)).expect("couldn't convert file path to URL");
+    let text_doc = TextDocumentIdentifier::new(url);
+    let messages = vec![
+        initialize(0, root_path.as_os_str().to_str().map(|x| x.to_owned())).to_string(),
+        request::<requests::Rename>(42, RenameParams {
+            text_document: text_doc,
+            position: env.cache.mk_ls_position(src(&source_file_path, 22, "world")),
+            new_name: "match".to_owned()
+        }).to_string(),
+    ];
+
+    let (mut server, results) = env.mock_server(messages);
+    // Initialize and build.
+    assert_eq!(ls_server::LsService::handle_message(&mut server),
+               ls_server::ServerStateChange::Continue);
+    expect_messages(results.clone(), &[ExpectedMessage::new(Some(0)).expect_contains("capabilities"),
+                                       ExpectedMessage::new(None).expect_contains("beginBuild"),
+                                       ExpectedMessage::new(None).expect_contains("diagnosticsBegin"),
+                                       ExpectedMessage::new(None).expect_contains("diagnosticsEnd")]);
+
+    assert_eq!(ls_server::LsService::handle_message(&mut server),
+               ls_server::ServerStateChange::Continue);
+    expect_messages(results.clone(), &[ExpectedMessage::new(Some(42)).expect_contains(r#""error""#)
+                                                                     .expect_contains("Can't rename: `match` is a keyword")]);
+}
+
+#[test]
+fn test_prepare_rename() {
+    let mut env = Environment::new("common");
+
+    let source_file_path = Path::new("src").join("main.rs");
+
+    let root_path = env.cache.abs_path(Path::new("."));
+    let url = Url::from_file_path(env.cache.abs_path(&source_file_path)).expect("couldn't convert file path to URL");
+    let messages = vec![
+        initialize(0, root_path.as_os_str().to_str().map(|x| x.to_owned())).to_string(),
+        request::<requests::PrepareRename>(42, TextDocumentPositionParams {
+            text_document: TextDocumentIdentifier::new(url.clone()),
+            position: env.cache.mk_ls_position(src(&source_file_path, 22, "world")),
+        }).to_string(),
+        request::<requests::PrepareRename>(43, TextDocumentPositionParams {
+            text_document: TextDocumentIdentifier::new(url),
+            position: env.cache.mk_ls_position(src(&source_file_path, 22, "println")),
+        }).to_string(),
+    ];
+
+    let (mut server, results) = env.mock_server(messages);
+    // Initialize and build.
+    assert_eq!(ls_server::LsService::handle_message(&mut server),
+               ls_server::ServerStateChange::Continue);
+    expect_messages(results.clone(), &[ExpectedMessage::new(Some(0)).expect_contains("capabilities"),
+                                       ExpectedMessage::new(None).expect_contains("beginBuild"),
+                                       ExpectedMessage::new(None).expect_contains("diagnosticsBegin"),
+                                       ExpectedMessage::new(None).expect_contains("diagnosticsEnd")]);
+
+    assert_eq!(ls_server::LsService::handle_message(&mut server),
+               ls_server::ServerStateChange::Continue);
+    expect_messages(results.clone(), &[ExpectedMessage::new(Some(42)).expect_contains(r#""result":{"start":{"line":21,"character":27},"end":{"line":21,"character":32}}"#)]);
+
+    // `println` is a macro of the standard library.
+    assert_eq!(ls_server::LsService::handle_message(&mut server),
+               ls_server::ServerStateChange::Continue);
+    expect_messages(results.clone(), &[ExpectedMessage::new(Some(43)).expect_contains("Can't rename")]);
+}
+
+#[test]
+fn test_rename_field_shorthand() {
+    let mut env = Environment::new("rename");
+
+    let source_file_path = Path::new("src").join("main.rs");
+
+    let root_path = env.cache.abs_path(Path::new("."));
+    let url = Url::from_file_path(env.cache.abs_path(&source_file_path)).expect("couldn't convert file path to URL");
+    let messages = vec![
+        initialize(0, root_path.as_os_str().to_str().map(|x| x.to_owned())).to_string(),
+        request::<requests::Rename>(42, RenameParams {
+            text_document: TextDocumentIdentifier::new(url),
+            position: env.cache.mk_ls_position(src(&source_file_path, 8, "x")),
+            new_name: "y".to_owned()
+        }).to_string(),
+    ];
+
+    let (mut server, results) = env.mock_server(messages);
+    // Initialize and build.
+    assert_eq!(ls_server::LsService::handle_message(&mut server),
+               ls_server::ServerStateChange::Continue);
+    expect_messages(results.clone(), &[ExpectedMessage::new(Some(0)).expect_contains("capabilities"),
+                                       ExpectedMessage::new(None).expect_contains("beginBuild"),
+                                       ExpectedMessage::new(None).expect_contains("diagnosticsBegin"),
+                                       ExpectedMessage::new(None).expect_contains("diagnosticsEnd")]);
+
+    // `Foo { x }` becomes `Foo { y: x }`, also in patterns (`Foo { y: ref x }`).
+    assert_eq!(ls_server::LsService::handle_message(&mut server),
+               ls_server::ServerStateChange::Continue);
+    expect_messages(results.clone(), &[ExpectedMessage::new(Some(42)).expect_contains(r#"{"range":{"start":{"line":7,"character":4},"end":{"line":7,"character":5}},"newText":"y"}"#)
+                                                                     .expect_contains(r#"{"range":{"start":{"line":11,"character":10},"end":{"line":11,"character":10}},"newText":"y: "}"#)
+                                                                     .expect_contains(r#"{"range":{"start":{"line":15,"character":14},"end":{"line":15,"character":14}},"newText":"y: "}"#)
+                                                                     .expect_contains(r#"{"range":{"start":{"line":20,"character":14},"end":{"line":20,"character":14}},"newText":"y: "}"#)]);
+}
+
+#[test]
+fn test_rename_local_shorthand() {
+    let mut env = Environment::new("rename");
+
+    let source_file_path = Path::new("src").join("main.rs");
+
+    let root_path = env.cache.abs_path(Path::new("."));
+    let url = Url::from_file_path(env.cache.abs_path(&source_file_path)).expect("couldn't convert file path to URL");
+    let messages = vec![
+        initialize(0, root_path.as_os_str().to_str().map(|x| x.to_owned())).to_string(),
+        request::<requests::Rename>(42, RenameParams {
+            text_document: TextDocumentIdentifier::new(url.clone()),
+            position: env.cache.mk_ls_position(src(&source_file_path, 17, "x")),
+            new_name: "y".to_owned()
+        }).to_string(),
+        request::<requests::Rename>(43, RenameParams {
+            text_document: TextDocumentIdentifier::new(url),
+            position: env.cache.mk_ls_position(src(&source_file_path, 22, "x")),
+            new_name: "y".to_owned()
+        }).to_string(),
+    ];
+
+    let (mut server, results) = env.mock_server(messages);
+    // Initialize and build.
+    assert_eq!(ls_server::LsService::handle_message(&mut server),
+               ls_server::ServerStateChange::Continue);
+    expect_messages(results.clone(), &[ExpectedMessage::new(Some(0)).expect_contains("capabilities"),
+                                       ExpectedMessage::new(None).expect_contains("beginBuild"),
+                                       ExpectedMessage::new(None).expect_contains("diagnosticsBegin"),
+                                       ExpectedMessage::new(None).expect_contains("diagnosticsEnd")]);
+
+    // `Foo { x }` becomes `Foo { x: y }`.
+    assert_eq!(ls_server::LsService::handle_message(&mut server),
+               ls_server::ServerStateChange::Continue);
+    expect_messages(results.clone(), &[ExpectedMessage::new(Some(42)).expect_contains(r#"{"range":{"start":{"line":15,"character":14},"end":{"line":15,"character":15}},"newText":"x: y"}"#)
+                                                                     .expect_contains(r#"{"range":{"start":{"line":16,"character":4},"end":{"line":16,"character":5}},"newText":"y"}"#)]);
+
+    // The binding mode goes with the binding: `Foo { x: ref y }`.
+    assert_eq!(ls_server::LsService::handle_message(&mut server),
+               ls_server::ServerStateChange::Continue);
+    expect_messages(results.clone(), &[ExpectedMessage::new(Some(43)).expect_contains(r#"{"range":{"start":{"line":20,"character":14},"end":{"line":20,"character":19}},"newText":"x: ref y"}"#)
+                                                                     .expect_contains(r#"{"range":{"start":{"line":21,"character":5},"end":{"line":21,"character":6}},"newText":"y"}"#)]);
+}
+
+#[test]
+fn test_rename_import_alias() {
+    let mut env = Environment::new("rename");
+
+    let source_file_path = Path::new("src").join("main.rs");
+
+    let root_path = env.cache.abs_path(Path::new("."));
+    let url = Url::from_file_path(env.cache.abs_path(&source_file_path)).expect("couldn't convert file path to URL");
+    let messages = vec![
+        initialize(0, root_path.as_os_str().to_str().map(|x| x.to_owned())).to_string(),
+        request::<requests::Rename>(42, RenameParams {
+            text_document: TextDocumentIdentifier::new(url.clone()),
+            position: env.cache.mk_ls_position(src(&source_file_path, 2, "b()")),
+            new_name: "e".to_owned()
+        }).to_string(),
+        request::<requests::Rename>(43, RenameParams {
+            text_document: TextDocumentIdentifier::new(url),
+            position: env.cache.mk_ls_position(src(&source_file_path, 26, "c")),
+            new_name: "d".to_owned()
+        }).to_string(),
+    ];
+
+    let (mut server, results) = env.mock_server(messages);
+    // Initialize and build.
+    assert_eq!(ls_server::LsService::handle_message(&mut server),
+               ls_server::ServerStateChange::Continue);
+    expect_messages(results.clone(), &[ExpectedMessage::new(Some(0)).expect_contains("capabilities"),
+                                       ExpectedMessage::new(None).expect_contains("beginBuild"),
+                                       ExpectedMessage::new(None).expect_contains("diagnosticsBegin"),
+                                       ExpectedMessage::new(None).expect_contains("diagnosticsEnd")]);
+
+    // Renaming the function keeps the alias it's used by: `use a::e as c;`.
+    assert_eq!(ls_server::LsService::handle_message(&mut server),
+               ls_server::ServerStateChange::Continue);
+    expect_messages(results.clone(), &[ExpectedMessage::new(Some(42)).expect_contains(r#"{"range":{"start":{"line":1,"character":11},"end":{"line":1,"character":12}},"newText":"e"}"#)
+                                                                     .expect_contains(r#"{"range":{"start":{"line":4,"character":7},"end":{"line":4,"character":8}},"newText":"e"}"#)
+                                                                     .expect_not_contains(r#""line":4,"character":12"#)
+                                                                     .expect_not_contains(r#""line":25"#)]);
+
+    // Renaming the alias leaves the function alone: `use a::b as d;`.
+    assert_eq!(ls_server::LsService::handle_message(&mut server),
+               ls_server::ServerStateChange::Continue);
+    expect_messages(results.clone(), &[ExpectedMessage::new(Some(43)).expect_contains(r#"{"range":{"start":{"line":4,"character":12},"end":{"line":4,"character":13}},"newText":"d"}"#)
+                                                                     .expect_contains(r#"{"range":{"start":{"line":25,"character":4},"end":{"line":25,"character":5}},"newText":"d"}"#)
+                                                                     .expect_not_contains(r#""line":1,"#)
+                                                                     .expect_not_contains(r#""line":4,"character":7"#)]);
+}
+
 #[test]
 fn test_reformat() {
     let mut env = Environment::new("reformat");
@@ -381,8 +716,103 @@ fn test_reformat() {
 
     assert_eq!(ls_server::LsService::handle_message(&mut server),
                ls_server::ServerStateChange::Continue);
-    expect_messages(results.clone(), &[ExpectedMessage::new(Some(42)).expect_contains(r#"{"start":{"line":0,"character":0},"end":{"line":12,"character":0}}"#)
-                                            .expect_contains(r#"newText":"// Copyright 2017 The Rust Project Developers. See the COPYRIGHT\n// file at the top-level directory of this distribution and at\n// http://rust-lang.org/COPYRIGHT.\n//\n// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or\n// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license\n// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your\n// option. This file may not be copied, modified, or distributed\n// except according to those terms.\n\npub mod foo;\npub fn main() {\n    let world = \"world\";\n    println!(\"Hello, {}!\", world);\n}"#)]);
+    expect_messages(results.clone(), &[ExpectedMessage::new(Some(42)).expect_contains(r#"[{"range":{"start":{"line":10,"character":0},"end":{"line":12,"character":0}}"#)
+                                            .expect_contains(r#"newText":"pub mod foo;\npub fn main() {\n    let world = \"world\";\n    println!(\"Hello, {}!\", world);\n}"#)]);
+}
+
+#[test]
+fn test_reformat_errors() {
+    let mut env = Environment::new("reformat_errors");
+
+    let source_file_path = Path::new("src").join("main.rs");
+
+    let root_path = env.cache.abs_path(Path::new("."));
+    let url = Url::from_file_path(env.cache.abs_path(&source_file_path)).expect("couldn't convert file path to URL");
+    let formatting = |id| request::<requests::Formatting>(id, DocumentFormattingParams {
+        text_document: TextDocumentIdentifier::new(url.clone()),
+        options: FormattingOptions {
+            tab_size: 4,
+            insert_spaces: true,
+            properties: ::std::collections::HashMap::new(),
+        },
+    }).to_string();
+    let messages = vec![
+        initialize(0, root_path.as_os_str().to_str().map(|x| x.to_owned())).to_string(),
+        formatting(42),
+        notification::<notifications::DidOpen>(DidOpenTextDocumentParams {
+            text_document: TextDocumentItem {
+                uri: url.clone(),
+                language_id: "rust".to_owned(),
+                version: 1,
+                text: "pub fn main() {\n    let world = \"world\"\n}\n".to_owned(),
+            },
+        }).to_string(),
+        formatting(43),
+    ];
+
+    let (mut server, results) = env.mock_server(messages);
+    // Initialize and build.
+    assert_eq!(ls_server::LsService::handle_message(&mut server),
+               ls_server::ServerStateChange::Continue);
+    expect_messages(results.clone(), &[ExpectedMessage::new(Some(0)).expect_contains("capabilities"),
+                                       ExpectedMessage::new(None).expect_contains("beginBuild"),
+                                       ExpectedMessage::new(None).expect_contains("diagnosticsBegin"),
+                                       ExpectedMessage::new(None).expect_contains("diagnosticsEnd")]);
+
+    // rustfmt's error about the line it can't format is published.
+    assert_eq!(ls_server::LsService::handle_message(&mut server),
+               ls_server::ServerStateChange::Continue);
+    expect_messages(results.clone(), &[ExpectedMessage::new(None).expect_contains("publishDiagnostics")
+                                            .expect_contains(r#""source":"rustfmt""#)
+                                            .expect_contains(r#""start":{"line":1"#)
+                                            .expect_contains("line exceeded maximum width"),
+                                       ExpectedMessage::new(Some(42))]);
+
+    // Open the file with contents that don't parse.
+    assert_eq!(ls_server::LsService::handle_message(&mut server),
+               ls_server::ServerStateChange::Continue);
+
+    // A file that doesn't parse can't be formatted. The error is left to
+    // the compiler, and rustfmt's previous error is cleared.
+    assert_eq!(ls_server::LsService::handle_message(&mut server),
+               ls_server::ServerStateChange::Continue);
+    expect_messages(results.clone(), &[ExpectedMessage::new(None).expect_contains("publishDiagnostics")
+                                            .expect_contains(r#""diagnostics":[]"#),
+                                       ExpectedMessage::new(Some(43)).expect_contains("Reformat failed")]);
+}
+
+#[test]
+fn test_format_on_save() {
+    let mut env = Environment::new("reformat");
+
+    let source_file_path = Path::new("src").join("main.rs");
+
+    let root_path = env.cache.abs_path(Path::new("."));
+    let url = Url::from_file_path(env.cache.abs_path(&source_file_path)).expect("couldn't convert file path to URL");
+    let text_doc = TextDocumentIdentifier::new(url);
+    let messages = vec![
+        initialize(0, root_path.as_os_str().to_str().map(|x| x.to_owned())).to_string(),
+        request::<requests::WillSaveWaitUntil>(42, WillSaveTextDocumentParams {
+            text_document: text_doc,
+            reason: TextDocumentSaveReason::Manual,
+        }).to_string(),
+    ];
+
+    env.with_config(|c| c.format_on_save = true);
+    let (mut server, results) = env.mock_server(messages);
+    // Initialize and build.
+    assert_eq!(ls_server::LsService::handle_message(&mut server),
+               ls_server::ServerStateChange::Continue);
+    expect_messages(results.clone(), &[ExpectedMessage::new(Some(0)).expect_contains("capabilities"),
+                                       ExpectedMessage::new(None).expect_contains("beginBuild"),
+                                       ExpectedMessage::new(None).expect_contains("diagnosticsBegin"),
+                                       ExpectedMessage::new(None).expect_contains("diagnosticsEnd")]);
+
+    // Only the unformatted lines are edited, not the copyright header.
+    assert_eq!(ls_server::LsService::handle_message(&mut server),
+               ls_server::ServerStateChange::Continue);
+    expect_messages(results.clone(), &[ExpectedMessage::new(Some(42)).expect_contains(r#"{"start":{"line":10,"character":0},"end":{"line":12,"character":0}}"#)
+                                            .expect_contains(r#"newText":"pub mod foo;\npub fn main() {\n    let world = \"world\";\n    println!(\"Hello, {}!\", world);\n}"#)]);
 }
 
 #[test]
@@ -421,8 +851,46 @@ fn test_reformat_with_range() {
 
     assert_eq!(ls_server::LsService::handle_message(&mut server),
                ls_server::ServerStateChange::Continue);
-    expect_messages(results.clone(), &[ExpectedMessage::new(Some(42)).expect_contains(r#"{"start":{"line":0,"character":0},"end":{"line":15,"character":5}}"#)
-                                            .expect_contains(r#"newText":"// Copyright 2017 The Rust Project Developers. See the COPYRIGHT\n// file at the top-level directory of this distribution and at\n// http://rust-lang.org/COPYRIGHT.\n//\n// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or\n// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license\n// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your\n// option. This file may not be copied, modified, or distributed\n// except according to those terms.\n\npub fn main() {\n    let world1 = \"world\";\n    println!(\"Hello, {}!\", world1);\n    let world2 = \"world\";\n    println!(\"Hello, {}!\", world2);\n    let world3 = \"world\";\n    println!(\"Hello, {}!\", world3);\n}\n"#)]);
+    // Only the selected statements are formatted, not the rest of `main`.
+    expect_messages(results.clone(), &[ExpectedMessage::new(Some(42)).expect_contains(r#""result":[{"range":{"start":{"line":12,"character":0},"end":{"line":13,"character":0}},"newText":"    let world1 = \"world\";\n    println!(\"Hello, {}!\", world1);\n"}]"#)]);
+}
+
+#[test]
+fn test_on_type_formatting() {
+    let mut env = Environment::new("reformat_with_range");
+    let source_file_path = Path::new("src").join("main.rs");
+
+    let root_path = env.cache.abs_path(Path::new("."));
+    let url = Url::from_file_path(env.cache.abs_path(&source_file_path)).expect("couldn't convert file path to URL");
+    let text_doc = TextDocumentIdentifier::new(url);
+    let messages = vec![
+        initialize(0, root_path.as_os_str().to_str().map(|x| x.to_owned())).to_string(),
+        request::<requests::OnTypeFormatting>(42, DocumentOnTypeFormattingParams {
+            text_document: text_doc,
+            position: Position { line: 12, character: 53 },
+            ch: ";".to_owned(),
+            options: FormattingOptions {
+                tab_size: 4,
+                insert_spaces: true,
+                properties: ::std::collections::HashMap::new(),
+            },
+        }).to_string(),
+    ];
+
+    let (mut server, results) = env.mock_server(messages);
+
+    // Initialize and build.
+    assert_eq!(ls_server::LsService::handle_message(&mut server),
+               ls_server::ServerStateChange::Continue);
+    expect_messages(results.clone(), &[ExpectedMessage::new(Some(0)).expect_contains("capabilities"),
+                                       ExpectedMessage::new(None).expect_contains("beginBuild"),
+                                       ExpectedMessage::new(None).expect_contains("diagnosticsBegin"),
+                                       ExpectedMessage::new(None).expect_contains("diagnosticsEnd")]);
+
+    // Only the statement ended by the `;` is formatted.
+    assert_eq!(ls_server::LsService::handle_message(&mut server),
+               ls_server::ServerStateChange::Continue);
+    expect_messages(results.clone(), &[ExpectedMessage::new(Some(42)).expect_contains(r#""result":[{"range":{"start":{"line":12,"character":0},"end":{"line":13,"character":0}},"newText":"    let world1 = \"world\";\n    println!(\"Hello, {}!\", world1);\n"}]"#)]);
 }
 
 #[test]
@@ -511,6 +979,74 @@ fn test_bin_lib_project() {
                                        ExpectedMessage::new(None).expect_contains("diagnosticsEnd")]);
 }
 
+#[test]
+fn test_all_targets() {
+    let mut env = Environment::new("all_targets");
+
+    let root_path = env.cache.abs_path(Path::new("."));
+
+    let messages = vec![
+        initialize(0, root_path.as_os_str().to_str().map(|x| x.to_owned())).to_string(),
+    ];
+
+    env.with_config(|c| c.all_targets = true);
+    let (mut server, results) = env.mock_server(messages);
+    // Initialize and build.
+    assert_eq!(ls_server::LsService::handle_message(&mut server),
+               ls_server::ServerStateChange::Continue);
+    expect_messages(results.clone(), &[ExpectedMessage::new(Some(0)).expect_contains("capabilities"),
+                                       ExpectedMessage::new(None).expect_contains("beginBuild"),
+                                       ExpectedMessage::new(None).expect_contains("diagnosticsBegin"),
+                                       ExpectedMessage::new(None).expect_contains("example.rs")
+                                                                 .expect_contains(r#""message":"mismatched types""#),
+                                       ExpectedMessage::new(None).expect_contains("diagnosticsEnd")]);
+}
+
+#[test]
+fn test_full_analysis_crates() {
+    let mut env = Environment::new("full_analysis_crates");
+
+    let root_path = env.cache.abs_path(Path::new("."));
+
+    let messages = vec![
+        initialize(0, root_path.as_os_str().to_str().map(|x| x.to_owned())).to_string(),
+    ];
+
+    env.with_config(|c| c.full_analysis_crates = vec!["dep".to_owned()]);
+    let (mut server, results) = env.mock_server(messages);
+    // Initialize and build.
+    assert_eq!(ls_server::LsService::handle_message(&mut server),
+               ls_server::ServerStateChange::Continue);
+    expect_messages(results.clone(), &[ExpectedMessage::new(Some(0)).expect_contains("capabilities"),
+                                       ExpectedMessage::new(None).expect_contains("beginBuild"),
+                                       ExpectedMessage::new(None).expect_contains("diagnosticsBegin"),
+                                       ExpectedMessage::new(None).expect_contains("lib.rs")
+                                                                 .expect_contains("unused variable"),
+                                       ExpectedMessage::new(None).expect_contains("diagnosticsEnd")]);
+}
+
+#[test]
+fn test_rust_project() {
+    let mut env = Environment::new("rust_project");
+
+    let root_path = env.cache.abs_path(Path::new("."));
+
+    let messages = vec![
+        initialize(0, root_path.as_os_str().to_str().map(|x| x.to_owned())).to_string(),
+    ];
+
+    let (mut server, results) = env.mock_server(messages);
+    // Initialize and build both crates without Cargo.
+    assert_eq!(ls_server::LsService::handle_message(&mut server),
+               ls_server::ServerStateChange::Continue);
+    expect_messages(results.clone(), &[ExpectedMessage::new(Some(0)).expect_contains("capabilities"),
+                                       ExpectedMessage::new(None).expect_contains("beginBuild"),
+                                       ExpectedMessage::new(None).expect_contains("diagnosticsBegin"),
+                                       ExpectedMessage::new(None).expect_contains("main.rs")
+                                                                 .expect_contains(r#""message":"mismatched types""#),
+                                       ExpectedMessage::new(None).expect_contains("diagnosticsEnd")]);
+}
+
 // FIXME(#524) timing issues when run concurrently with `test_bin_lib_project`
 // #[test]
 // fn test_bin_lib_project_no_cfg_test() {
@@ -0,0 +1,6 @@
+extern crate all_targets;
+
+fn main() {
+    let answer: String = all_targets::answer();
+    println!("{}", answer);
+}
@@ -0,0 +1,5 @@
+extern crate all_targets;
+
+fn main() {
+    println!("{}", all_targets::answer());
+}
@@ -0,0 +1,6 @@
+extern crate all_targets;
+
+#[test]
+fn answer() {
+    assert_eq!(all_targets::answer(), 42);
+}
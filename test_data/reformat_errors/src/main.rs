@@ -0,0 +1,4 @@
+pub fn main() {
+    let message = "This string literal is far too long to fit within the maximum line width, so rustfmt reports it.";
+    println!("{}", message);
+}
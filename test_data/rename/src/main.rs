@@ -0,0 +1,29 @@
+mod a {
+    pub fn b() {}
+}
+
+use a::b as c;
+
+struct Foo {
+    x: u32,
+}
+
+fn field(x: u32) -> Foo {
+    Foo { x }
+}
+
+fn local(foo: Foo) -> u32 {
+    let Foo { x } = foo;
+    x
+}
+
+fn local_ref(foo: &Foo) -> u32 {
+    let Foo { ref x } = *foo;
+    *x
+}
+
+fn main() {
+    c();
+    local(field(1));
+    local_ref(&field(2));
+}
@@ -0,0 +1,6 @@
+extern crate foo;
+
+fn main() {
+    let answer: String = foo::answer();
+    println!("{}", answer);
+}
@@ -0,0 +1,73 @@
+// Copyright 2017 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Runs the RLS as the rustc child process used with `rustc_out_of_process`.
+//! This is an integration test, so that Cargo builds the RLS binary, which the
+//! RLS's own tests run as the child as well.
+
+extern crate serde_json;
+
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+fn rls_executable() -> PathBuf {
+    let exe = env::current_exe().unwrap();
+    // This test is in `target/<profile>/deps`.
+    exe.parent().unwrap().parent().unwrap().join(format!("rls{}", env::consts::EXE_SUFFIX))
+}
+
+fn sysroot() -> String {
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| "rustc".to_owned());
+    let output = Command::new(rustc).args(&["--print", "sysroot"]).output().unwrap();
+    String::from_utf8(output.stdout).unwrap().trim().to_owned()
+}
+
+#[test]
+fn test_rustc_child() {
+    let project_dir = env::current_dir().unwrap().join("test_data").join("borrow_error");
+    let main = project_dir.join("src").join("main.rs");
+    let out_dir = env::current_dir().unwrap().join("target").join("tests").join("rustc_child");
+    fs::create_dir_all(&out_dir).unwrap();
+
+    let mut child = Command::new(rls_executable())
+        .arg(&main)
+        .args(&["--crate-name", "borrow_error", "--crate-type", "bin", "--error-format=json",
+                "--emit=metadata", "--out-dir"])
+        .arg(&out_dir)
+        .args(&["--sysroot", &sysroot()])
+        .env("RLS_RUSTC_SHIM", "1")
+        .env("RLS_RUSTC_CHILD", "1")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    // The unsaved contents of the file are used, rather than the ones on disk.
+    let mut changed = HashMap::new();
+    changed.insert(main.clone(), "fn main() {\n    let x: u32 = \"\";\n}\n".to_owned());
+    serde_json::to_writer(child.stdin.take().unwrap(), &changed).unwrap();
+
+    let output = child.wait_with_output().unwrap();
+    fs::remove_dir_all(&out_dir).unwrap();
+    assert!(output.status.success());
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let output: serde_json::Value = serde_json::from_str(stdout.lines().last().unwrap()).unwrap();
+    assert_eq!(output["success"], serde_json::Value::Bool(false));
+    let messages: Vec<_> = output["messages"].as_array().unwrap().iter()
+        .map(|message| message.as_str().unwrap().to_owned())
+        .collect();
+    assert!(messages.iter().any(|message| message.contains(r#""message":"mismatched types""#)),
+            "unexpected messages: {:?}", messages);
+    assert!(!messages.iter().any(|message| message.contains("cannot borrow")));
+}